@@ -1,18 +1,154 @@
-use std::alloc::{AllocError, Allocator, Global, Layout};
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
+use std::sync::Arc;
 
+/// Alignment of [`AlignedBuffer`], enough for the widest vector loads.
+pub const ALIGNMENT: usize = 64;
 
-#[derive(Copy, Clone, Default, Debug)]
-pub struct AlignedAllocator<const ALIGNMENT: usize = 64>;
+/// One aligned allocation that is split up into [`AlignedBuffer`]s. The memory is freed once the
+/// arena and every buffer taken from it are dropped.
+pub struct Arena {
+    memory: Arc<Memory>,
+    used: usize,
+}
+
+/// The allocation behind an [`Arena`].
+struct Memory {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// SAFETY: The memory is only accessed through the disjoint buffers taken from the arena
+unsafe impl Send for Memory {}
+unsafe impl Sync for Memory {}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            // SAFETY: The memory was allocated in `Arena::new` with the same layout
+            unsafe { dealloc(self.ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+impl Arena {
+    /// Allocates `size` bytes, the sum of [`Arena::size_of`] for every buffer that is taken.
+    pub fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, ALIGNMENT).expect("arena too large");
+        let ptr = if size == 0 {
+            NonNull::new(std::ptr::without_provenance_mut(ALIGNMENT)).unwrap()
+        } else {
+            // SAFETY: The size of the layout is not zero
+            let ptr = unsafe { alloc(layout) };
+            let Some(ptr) = NonNull::new(ptr) else {
+                handle_alloc_error(layout)
+            };
+            ptr
+        };
+
+        Self {
+            memory: Arc::new(Memory { ptr, layout }),
+            used: 0,
+        }
+    }
 
-unsafe impl<const ALIGNMENT: usize> Allocator for AlignedAllocator<ALIGNMENT> {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        Global.allocate(layout.align_to(ALIGNMENT).unwrap())
+    /// The space a buffer of `len` elements takes up in an arena, including the padding that
+    /// aligns the next buffer.
+    pub fn size_of<T>(len: usize) -> usize {
+        len.checked_mul(size_of::<T>())
+            .and_then(|size| size.checked_next_multiple_of(ALIGNMENT))
+            .expect("buffer too large")
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        unsafe { Global.deallocate(ptr, layout.align_to(ALIGNMENT).unwrap()) }
+    /// The size of the arena in bytes.
+    pub fn size(&self) -> usize {
+        self.memory.layout.size()
     }
+
+    /// The number of bytes that have not been taken yet.
+    pub fn remaining(&self) -> usize {
+        self.size() - self.used
+    }
+
+    /// Takes a buffer with `len` copies of `value`. Panics if the arena is too small.
+    pub fn alloc<T: Copy>(&mut self, len: usize, value: T) -> AlignedBuffer<T> {
+        assert!(align_of::<T>() <= ALIGNMENT);
+        let size = Self::size_of::<T>(len);
+        assert!(size <= self.remaining(), "arena too small");
+
+        // SAFETY: The buffer lies within the allocation, and starts at a multiple of `ALIGNMENT`
+        let ptr = unsafe { self.memory.ptr.add(self.used) }.cast::<T>();
+        for i in 0..len {
+            // SAFETY: The buffer has room for `len` elements
+            unsafe { ptr.as_ptr().add(i).write(value) };
+        }
+        self.used += size;
+
+        AlignedBuffer {
+            ptr,
+            len,
+            _memory: self.memory.clone(),
+        }
+    }
+}
+
+/// A fixed-size buffer whose first element is aligned to [`ALIGNMENT`] bytes, taken from an
+/// [`Arena`].
+pub struct AlignedBuffer<T: Copy> {
+    ptr: NonNull<T>,
+    len: usize,
+    /// Keeps the arena alive.
+    _memory: Arc<Memory>,
 }
 
-pub static ALIGNED: AlignedAllocator<64> = AlignedAllocator;
+// SAFETY: The buffer owns its elements like a `Box<[T]>`, no other buffer overlaps them
+unsafe impl<T: Copy + Send> Send for AlignedBuffer<T> {}
+unsafe impl<T: Copy + Sync> Sync for AlignedBuffer<T> {}
+
+impl<T: Copy> AlignedBuffer<T> {
+    /// Allocates a buffer with `len` copies of `value` in its own arena.
+    pub fn new(len: usize, value: T) -> Self {
+        Arena::new(Arena::size_of::<T>(len)).alloc(len, value)
+    }
+}
+
+impl<T: Copy> Deref for AlignedBuffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: All `len` elements were initialized in `Arena::alloc`
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy> DerefMut for AlignedBuffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: All `len` elements were initialized in `Arena::alloc`
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy + std::fmt::Debug> std::fmt::Debug for AlignedBuffer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+impl<'a, T: Copy> IntoIterator for &'a AlignedBuffer<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: Copy> IntoIterator for &'a mut AlignedBuffer<T> {
+    type Item = &'a mut T;
+    type IntoIter = std::slice::IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
@@ -0,0 +1,173 @@
+use std::io::{Cursor, ErrorKind};
+use std::ops::ControlFlow;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::loader::LoadError;
+
+/// How much of an impulse response may be decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_seconds: f32,
+    /// Decodes only the first `max_seconds` of longer impulse responses, instead of rejecting
+    /// them.
+    pub truncate: bool,
+    /// The memory budget in bytes, which the file and the decoded samples count towards.
+    pub budget: usize,
+}
+
+/// An impulse response at the sample rate of its file.
+#[derive(Debug)]
+pub struct DecodedIr {
+    pub sample_rate: u32,
+    /// The samples of every channel, which all have the same length.
+    pub channels: Vec<Vec<f32>>,
+    /// Whether decoding stopped at the maximum length, because the file is longer.
+    pub truncated: bool,
+}
+
+/// Decodes the first audio track of the file in `bytes`. When the file states its length, the
+/// length and the memory of the decoded samples are checked against `limits` before anything is
+/// decoded. Otherwise they are checked while decoding.
+///
+/// `on_progress` is called after every packet with the fraction of the samples that are decoded,
+/// which stays at zero for files that don't state their length. Returns `Ok(None)` if it breaks.
+pub fn decode(
+    bytes: Vec<u8>,
+    limits: Limits,
+    mut on_progress: impl FnMut(f32) -> ControlFlow<()>,
+) -> Result<Option<DecodedIr>, LoadError> {
+    let over_budget = || LoadError::OverBudget {
+        budget: limits.budget,
+    };
+    let max_bytes = limits
+        .budget
+        .checked_sub(bytes.len())
+        .ok_or_else(over_budget)?;
+
+    let source = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(unsupported)?;
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(LoadError::Empty)?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let sample_rate = params
+        .sample_rate
+        .filter(|&sample_rate| sample_rate > 0)
+        .ok_or_else(|| LoadError::Unsupported("The sample rate is unknown".into()))?;
+    let max_frames = (limits.max_seconds * sample_rate as f32) as usize;
+    let too_long = |frames: usize| LoadError::TooLong {
+        seconds: frames as f32 / sample_rate as f32,
+        max_seconds: limits.max_seconds,
+    };
+
+    let expected_frames = params.n_frames.map(|frames| frames as usize);
+    if let Some(frames) = expected_frames {
+        if frames > max_frames && !limits.truncate {
+            return Err(too_long(frames));
+        }
+        let num_channels = params.channels.map_or(1, |channels| channels.count());
+        if num_channels * usize::min(frames, max_frames) * size_of::<f32>() > max_bytes {
+            return Err(over_budget());
+        }
+    }
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&params, &DecoderOptions::default())
+        .map_err(unsupported)?;
+    let mut channels: Vec<Vec<f32>> = Vec::new();
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    let mut frames = 0;
+    let mut truncated = false;
+    let frames_to_decode = expected_frames.map(|frames| usize::min(frames, max_frames));
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(error)) if error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(unsupported(error)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        if frames >= max_frames {
+            if limits.truncate {
+                truncated = true;
+                break;
+            }
+            // Only the length is needed for the error from here on, which the packets tell
+            // without being decoded. Their duration is in samples for audio tracks.
+            frames += packet.dur as usize;
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Like a player, skip the packets that are corrupted
+            Err(Error::DecodeError(_)) => continue,
+            Err(error) => return Err(unsupported(error)),
+        };
+        let spec = *decoded.spec();
+        if channels.is_empty() {
+            let capacity = frames_to_decode.unwrap_or(0);
+            channels = (0..spec.channels.count())
+                .map(|_| Vec::with_capacity(capacity))
+                .collect();
+        }
+        let buffer =
+            buffer.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        buffer.copy_planar_ref(decoded);
+
+        let samples = buffer.samples();
+        let length = samples.len() / channels.len();
+        if length == 0 {
+            continue;
+        }
+        for (channel, samples) in channels.iter_mut().zip(samples.chunks_exact(length)) {
+            channel.extend_from_slice(samples);
+        }
+        frames += length;
+        if channels.len() * frames * size_of::<f32>() > max_bytes {
+            return Err(over_budget());
+        }
+        let progress = frames_to_decode.map_or(0.0, |total| frames as f32 / total as f32);
+        if on_progress(f32::min(progress, 1.0)).is_break() {
+            return Ok(None);
+        }
+    }
+
+    if frames > max_frames && !limits.truncate {
+        return Err(too_long(frames));
+    }
+    if channels.is_empty() || frames == 0 {
+        return Err(LoadError::Empty);
+    }
+
+    Ok(Some(DecodedIr {
+        sample_rate,
+        channels,
+        truncated,
+    }))
+}
+
+fn unsupported(error: Error) -> LoadError {
+    LoadError::Unsupported(error.to_string())
+}
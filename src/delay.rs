@@ -0,0 +1,96 @@
+/// A delay line with a fixed maximum delay. Changing the delay does not allocate.
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+    delay: usize,
+    /// The delay that is faded out after the delay changed.
+    previous_delay: usize,
+    crossfade_length: usize,
+    crossfade_position: usize,
+}
+
+impl DelayLine {
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_delay + 1],
+            position: 0,
+            delay: 0,
+            previous_delay: 0,
+            crossfade_length: 0,
+            crossfade_position: 0,
+        }
+    }
+
+    /// Sets the delay in samples, which must not exceed the maximum delay. The output at the
+    /// previous delay is faded out over `crossfade_length` samples while the output at the new
+    /// delay is faded in, so the delay can change without a click. An interrupted crossfade is
+    /// cut short.
+    pub fn set_delay(&mut self, delay: usize, crossfade_length: usize) {
+        debug_assert!(delay < self.buffer.len());
+        let delay = usize::min(delay, self.buffer.len() - 1);
+        if delay == self.delay {
+            return;
+        }
+
+        self.previous_delay = self.delay;
+        self.delay = delay;
+        self.crossfade_length = crossfade_length;
+        self.crossfade_position = 0;
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.fill(0.0);
+        self.position = 0;
+        self.crossfade_position = self.crossfade_length;
+    }
+
+    /// Delays `samples` in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let length = self.buffer.len();
+
+        for s in samples {
+            self.buffer[self.position] = *s;
+            *s = self.buffer[(self.position + length - self.delay) % length];
+
+            // Both delays carry the same signal, so the gains add up to one rather than their
+            // powers
+            if self.crossfade_position < self.crossfade_length {
+                let t = self.crossfade_position as f32 / self.crossfade_length as f32;
+                let previous = self.buffer[(self.position + length - self.previous_delay) % length];
+                *s = *s * t + previous * (1.0 - t);
+                self.crossfade_position += 1;
+            }
+
+            self.position += 1;
+            if self.position == length {
+                self.position = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DelayLine;
+
+    #[test]
+    fn crossfades_between_delays() {
+        let mut delay = DelayLine::new(4);
+        delay.set_delay(2, 0);
+        let mut samples = [1.0; 8];
+        delay.process(&mut samples);
+        assert_eq!(samples, [0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+
+        // A constant signal stays constant, it's only the timing that changes
+        delay.set_delay(4, 4);
+        let mut samples = [1.0; 8];
+        delay.process(&mut samples);
+        assert_eq!(samples, [1.0; 8]);
+
+        let mut delay = DelayLine::new(4);
+        delay.set_delay(4, 4);
+        let mut samples = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        delay.process(&mut samples);
+        assert_eq!(samples, [1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+    }
+}
@@ -1,13 +1,12 @@
-use nih_plug::context::gui::AsyncExecutor;
 use nih_plug::prelude::Editor;
 use std::sync::Arc;
+use std::time::Duration;
 use vizia_plug::vizia::prelude::*;
 use vizia_plug::widgets::*;
 use vizia_plug::{create_vizia_editor, ViziaState, ViziaTheming};
 
 use crate::browser::{FileChooser, FileChooserModifiers};
-use crate::BackgroundTask;
-use crate::ConvolutionReverb;
+use crate::loader::{IrLoader, LoadError};
 use crate::PlugParams;
 
 pub const NOTO_SANS: &str = "Noto Sans";
@@ -15,22 +14,49 @@ pub const NOTO_SANS: &str = "Noto Sans";
 #[derive(Lens)]
 struct AppData {
     params: Arc<PlugParams>,
-    async_executor: AsyncExecutor<ConvolutionReverb>,
+    loader: Arc<IrLoader>,
+    /// Why the latest impulse response could not be loaded, or empty.
+    load_error: String,
+    /// What the loader is doing, or empty.
+    load_stage: String,
+    /// How much of `load_stage` is done, between zero and one.
+    load_progress: f32,
 }
 
 #[derive(Debug)]
 pub enum AppEvent {
     OpenImpuseResponse(String),
+    /// Picks up the state of the loader, which changes on the background thread.
+    PollLoader,
 }
 
 impl Model for AppData {
     fn event(&mut self, _: &mut EventContext, event: &mut Event) {
         event.map(|app_event, _| match app_event {
-            AppEvent::OpenImpuseResponse(f) => {
-                let file = std::fs::read(&f).expect("Failed to read the impule!");
+            AppEvent::OpenImpuseResponse(f) => match std::fs::read(&f) {
+                // The audio thread picks up the new generation and has the engines built
+                Ok(file) => {
+                    self.loader.request(file);
+                }
+                Err(error) => self.loader.report(LoadError::Io(Arc::new(error))),
+            },
+            AppEvent::PollLoader => {
+                let load_error = self
+                    .loader
+                    .error()
+                    .map_or_else(String::new, |error| error.to_string());
+                if load_error != self.load_error {
+                    self.load_error = load_error;
+                }
 
-                self.async_executor
-                    .execute_background(BackgroundTask::OpenImpulse(file))
+                let (stage, progress) = self.loader.progress();
+                let load_stage = stage.to_string();
+                if load_stage != self.load_stage {
+                    self.load_stage = load_stage;
+                }
+                if progress != self.load_progress {
+                    self.load_progress = progress;
+                }
             }
         });
     }
@@ -44,7 +70,7 @@ pub(crate) fn default_state() -> Arc<ViziaState> {
 pub(crate) fn create(
     params: Arc<PlugParams>,
     editor_state: Arc<ViziaState>,
-    async_executor: AsyncExecutor<ConvolutionReverb>,
+    loader: Arc<IrLoader>,
 ) -> Option<Box<dyn Editor>> {
     create_vizia_editor(
         editor_state,
@@ -52,10 +78,20 @@ pub(crate) fn create(
         move |cx, _gui_context| {
             AppData {
                 params: params.clone(),
-                async_executor: async_executor.clone(),
+                loader: loader.clone(),
+                load_error: String::new(),
+                load_stage: String::new(),
+                load_progress: 0.0,
             }
             .build(cx);
 
+            let poll = cx.add_timer(Duration::from_millis(100), None, |cx, action| {
+                if let TimerAction::Tick(_) = action {
+                    cx.emit(AppEvent::PollLoader);
+                }
+            });
+            cx.start_timer(poll);
+
             VStack::new(cx, |cx| {
                 Label::new(cx, "Gain GUI")
                     .font_family(vec![FamilyOwned::Named(String::from(NOTO_SANS))])
@@ -67,9 +103,54 @@ pub(crate) fn create(
                 Label::new(cx, "Mix");
                 ParamSlider::new(cx, AppData::params, |params| &params.mix);
 
-                ParamButton::new(cx, AppData::params, |params| &params.bypassed);
+                Label::new(cx, "IR Crossfade");
+                ParamSlider::new(cx, AppData::params, |params| &params.crossfade);
+
+                Label::new(cx, "Latency");
+                ParamSlider::new(cx, AppData::params, |params| &params.latency);
+
+                Label::new(cx, "Quality");
+                ParamSlider::new(cx, AppData::params, |params| &params.quality);
+
+                Label::new(cx, "IR Storage");
+                ParamSlider::new(cx, AppData::params, |params| &params.ir_storage);
+
+                Label::new(cx, "Max IR Length");
+                ParamSlider::new(cx, AppData::params, |params| &params.max_ir_length);
+
+                Label::new(cx, "Memory Budget");
+                ParamSlider::new(cx, AppData::params, |params| &params.memory_budget);
+
+                Label::new(cx, "Routing");
+                ParamSlider::new(cx, AppData::params, |params| &params.routing);
+
+                HStack::new(cx, |cx| {
+                    ParamButton::new(cx, AppData::params, |params| &params.swap_channels);
+                    ParamButton::new(cx, AppData::params, |params| &params.invert_left);
+                    ParamButton::new(cx, AppData::params, |params| &params.invert_right);
+                })
+                .gap(Pixels(5.0));
+
+                HStack::new(cx, |cx| {
+                    ParamButton::new(cx, AppData::params, |params| &params.bypassed);
+                    ParamButton::new(cx, AppData::params, |params| &params.bypass_input_only);
+                })
+                .gap(Pixels(5.0));
+
+                HStack::new(cx, |cx| {
+                    ParamButton::new(cx, AppData::params, |params| &params.truncate_long_irs);
+                    ParamButton::new(cx, AppData::params, |params| &params.threaded_tail);
+                })
+                .gap(Pixels(5.0));
 
                 FileChooser::new(cx).on_pick(|cx, f| cx.emit(AppEvent::OpenImpuseResponse(f)));
+                // The current engines keep playing while the new ones load
+                Label::new(cx, AppData::load_stage);
+                Element::new(cx)
+                    .height(Pixels(4.0))
+                    .width(AppData::load_progress.map(|progress| Percentage(progress * 100.0)))
+                    .background_color(Color::rgb(80, 160, 220));
+                Label::new(cx, AppData::load_error).color(Color::rgb(200, 40, 40));
             })
             .gap(Pixels(5.0))
             .border_width(Pixels(20.0))
@@ -1,47 +1,117 @@
-use std::sync::Arc;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
 
 use realfft::ComplexToReal;
+use realfft::FftNum;
 use realfft::RealFftPlanner;
 use realfft::RealToComplex;
 use rustfft::num_complex::Complex;
 
-pub struct FFT {
-    r2c: Arc<dyn RealToComplex<f32>>,
-    c2r: Arc<dyn ComplexToReal<f32>>,
-    r_input_buffer: Vec<f32>,
-    c_input_buffer: Vec<Complex<f32>>,
-    r_scratch: Vec<Complex<f32>>,
-    c_scratch: Vec<Complex<f32>>,
+use crate::allocator::{AlignedBuffer, Arena};
+
+/// Plans of any sample type, keyed by the type and the FFT size.
+type PlanCache = HashMap<(TypeId, usize), Box<dyn Any + Send>>;
+
+/// Every plan made so far. The plans are immutable, so all engines of all plugin instances share
+/// them.
+static PLANS: LazyLock<Mutex<PlanCache>> = LazyLock::new(Default::default);
+
+/// The forward and inverse transforms of one size. Planning comes before allocating the buffers
+/// of an [`FFT`], since their sizes depend on the plan.
+#[derive(Clone)]
+pub struct FftPlan<T: FftNum> {
+    r2c: Arc<dyn RealToComplex<T>>,
+    c2r: Arc<dyn ComplexToReal<T>>,
 }
-impl FFT {
-    pub fn new(fft_size: usize) -> Self {
-        let mut real_planner = RealFftPlanner::new();
-        let r2c = real_planner.plan_fft_forward(fft_size);
-        let c2r = real_planner.plan_fft_inverse(fft_size);
-        let r_input_buffer = r2c.make_input_vec();
-        let c_input_buffer = c2r.make_input_vec();
-        let r_scratch = r2c.make_scratch_vec();
-        let c_scratch = c2r.make_scratch_vec();
+
+impl<T: FftNum> FftPlan<T> {
+    /// The plan for `fft_size`, which is only planned the first time it is needed in the process.
+    pub fn shared(fft_size: usize) -> Self {
+        // A panic while planning leaves the cache without the new plan, which is still consistent
+        let mut plans = PLANS.lock().unwrap_or_else(PoisonError::into_inner);
+        plans
+            .entry((TypeId::of::<T>(), fft_size))
+            .or_insert_with(|| {
+                let mut real_planner = RealFftPlanner::new();
+                Box::new(Self {
+                    r2c: real_planner.plan_fft_forward(fft_size),
+                    c2r: real_planner.plan_fft_inverse(fft_size),
+                })
+            })
+            .downcast_ref::<Self>()
+            .expect("plans are keyed by their type")
+            .clone()
+    }
+
+    pub fn complex_len(&self) -> usize {
+        self.r2c.complex_len()
+    }
+
+    /// The space the buffers of an [`FFT`] with this plan take up in an [`Arena`].
+    pub fn arena_size(&self) -> usize {
+        Arena::size_of::<T>(self.r2c.len())
+            + Arena::size_of::<Complex<T>>(self.c2r.complex_len())
+            + Arena::size_of::<Complex<T>>(self.r2c.get_scratch_len())
+            + Arena::size_of::<Complex<T>>(self.c2r.get_scratch_len())
+    }
+}
+
+pub struct FFT<T: FftNum = f32> {
+    r2c: Arc<dyn RealToComplex<T>>,
+    c2r: Arc<dyn ComplexToReal<T>>,
+    r_input_buffer: AlignedBuffer<T>,
+    c_input_buffer: AlignedBuffer<Complex<T>>,
+    r_scratch: AlignedBuffer<Complex<T>>,
+    c_scratch: AlignedBuffer<Complex<T>>,
+}
+impl<T: FftNum> FFT<T> {
+    /// Takes the buffers from `arena`, which needs [`FftPlan::arena_size`] bytes of room.
+    pub fn new_in(plan: FftPlan<T>, arena: &mut Arena) -> Self {
+        let zero = Complex::new(T::zero(), T::zero());
+        let r_input_buffer = arena.alloc(plan.r2c.len(), T::zero());
+        let c_input_buffer = arena.alloc(plan.c2r.complex_len(), zero);
+        let r_scratch = arena.alloc(plan.r2c.get_scratch_len(), zero);
+        let c_scratch = arena.alloc(plan.c2r.get_scratch_len(), zero);
 
         Self {
-            r2c,
-            c2r,
+            r2c: plan.r2c,
+            c2r: plan.c2r,
             r_input_buffer,
             c_input_buffer,
             r_scratch,
             c_scratch,
         }
     }
-    pub fn forward_transform(&mut self, input: &[f32], output: &mut [Complex<f32>]) {
+    pub fn forward_transform(&mut self, input: &[T], output: &mut [Complex<T>]) {
         self.r_input_buffer.copy_from_slice(input);
         self.r2c
             .process_with_scratch(&mut self.r_input_buffer, output, &mut self.r_scratch)
             .unwrap();
     }
-    pub fn inverse_transform(&mut self, input: &[Complex<f32>], output: &mut [f32]) {
+    pub fn inverse_transform(&mut self, input: &[Complex<T>], output: &mut [T]) {
         self.c_input_buffer.copy_from_slice(input);
         self.c2r
             .process_with_scratch(&mut self.c_input_buffer, output, &mut self.c_scratch)
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::FftPlan;
+    use std::sync::Arc;
+
+    #[test]
+    fn plans_are_shared() {
+        let plan = FftPlan::<f32>::shared(512);
+        let same = FftPlan::<f32>::shared(512);
+        assert!(Arc::ptr_eq(&plan.r2c, &same.r2c));
+        assert!(Arc::ptr_eq(&plan.c2r, &same.c2r));
+
+        let other_size = FftPlan::<f32>::shared(1024);
+        assert_eq!(other_size.r2c.len(), 1024);
+        let other_type = FftPlan::<f64>::shared(512);
+        assert_eq!(other_type.r2c.len(), 512);
+    }
+}
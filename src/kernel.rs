@@ -0,0 +1,781 @@
+use crate::allocator::{AlignedBuffer, Arena};
+
+use half::{bf16, f16};
+use rustfft::num_complex::Complex;
+use std::ops::{Add, Mul, Range, Sub};
+use std::sync::OnceLock;
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Number of bins that are processed together.
+pub const LANES: usize = 16;
+
+/// A set of spectra with the same number of bins, stored as separate real and imaginary parts.
+///
+/// The bins are grouped in blocks of [`LANES`], and each block holds that group of bins for every
+/// spectrum before the next group starts. This way [`accumulate`] reads the partitions of a group
+/// from consecutive memory while the sum stays in registers.
+pub struct SplitSpectra<T: Copy + Default> {
+    re: AlignedBuffer<T>,
+    im: AlignedBuffer<T>,
+    num_bins: usize,
+    num_spectra: usize,
+}
+
+impl<T: Copy + Default> SplitSpectra<T> {
+    /// Takes the spectra from `arena`, which needs [`SplitSpectra::arena_size`] bytes of room.
+    pub fn new_in(arena: &mut Arena, num_bins: usize, num_spectra: usize) -> Self {
+        let len = Self::len(num_bins, num_spectra);
+
+        Self {
+            re: arena.alloc(len, T::default()),
+            im: arena.alloc(len, T::default()),
+            num_bins,
+            num_spectra,
+        }
+    }
+
+    /// The space `num_spectra` spectra with `num_bins` bins take up in an [`Arena`].
+    pub fn arena_size(num_bins: usize, num_spectra: usize) -> usize {
+        2 * Arena::size_of::<T>(Self::len(num_bins, num_spectra))
+    }
+
+    fn len(num_bins: usize, num_spectra: usize) -> usize {
+        num_bins.next_multiple_of(LANES) * num_spectra
+    }
+
+    fn block(&self, group: usize, spectrum: usize) -> Range<usize> {
+        let start = (group * self.num_spectra + spectrum) * LANES;
+        start..start + LANES
+    }
+
+    fn num_groups(&self) -> usize {
+        self.num_bins.div_ceil(LANES)
+    }
+
+    pub fn fill_zero(&mut self) {
+        self.re.fill(T::default());
+        self.im.fill(T::default());
+    }
+
+    /// Stores an interleaved spectrum with `num_bins` bins as spectrum `index`, narrowed to `T`.
+    pub fn store<S: Copy>(&mut self, index: usize, spectrum: &[Complex<S>])
+    where
+        T: Stored<S>,
+    {
+        for (group, bins) in spectrum[..self.num_bins].chunks(LANES).enumerate() {
+            let block = self.block(group, index);
+            for ((re, im), bin) in self.re[block.clone()]
+                .iter_mut()
+                .zip(&mut self.im[block])
+                .zip(bins)
+            {
+                *re = T::narrow(bin.re);
+                *im = T::narrow(bin.im);
+            }
+        }
+    }
+
+    /// Writes spectrum `index` to an interleaved spectrum with `num_bins` bins.
+    pub fn load(&self, index: usize, spectrum: &mut [Complex<T>]) {
+        for (group, bins) in spectrum[..self.num_bins].chunks_mut(LANES).enumerate() {
+            let block = self.block(group, index);
+            for ((re, im), bin) in self.re[block.clone()].iter().zip(&self.im[block]).zip(bins) {
+                *bin = Complex::new(*re, *im);
+            }
+        }
+    }
+}
+
+/// The instruction sets with a specialized version of [`accumulate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionSet {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Sse2,
+    /// AVX2 together with FMA.
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "x86_64")]
+    Avx512,
+}
+
+impl InstructionSet {
+    /// The fastest instruction set supported by this CPU. It is only detected once.
+    pub fn detect() -> Self {
+        static DETECTED: OnceLock<InstructionSet> = OnceLock::new();
+
+        *DETECTED.get_or_init(|| {
+            [
+                #[cfg(target_arch = "x86_64")]
+                InstructionSet::Avx512,
+                #[cfg(target_arch = "x86_64")]
+                InstructionSet::Avx2,
+                #[cfg(target_arch = "x86_64")]
+                InstructionSet::Sse2,
+            ]
+            .into_iter()
+            .find(|set| set.is_supported())
+            .unwrap_or(InstructionSet::Scalar)
+        })
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            InstructionSet::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Avx2 => {
+                is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+            }
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Avx512 => is_x86_feature_detected!("avx512f"),
+        }
+    }
+}
+
+/// The sample types [`accumulate`] works on, with the registers that hold [`LANES`] of them for
+/// every instruction set.
+pub trait Element: Copy + Default + Send + Sync + 'static {
+    /// Converts from the `f32` that the narrow [`Stored`] types are widened to.
+    fn from_single(value: f32) -> Self;
+    fn to_single(self) -> f32;
+
+    type Scalar: Block<Self>;
+    #[cfg(target_arch = "x86_64")]
+    type Sse2: Block<Self>;
+    #[cfg(target_arch = "x86_64")]
+    type Avx2: Block<Self>;
+    #[cfg(target_arch = "x86_64")]
+    type Avx512: Block<Self>;
+}
+
+impl Element for f32 {
+    #[inline(always)]
+    fn from_single(value: f32) -> Self {
+        value
+    }
+
+    fn to_single(self) -> f32 {
+        self
+    }
+
+    type Scalar = Scalar<f32>;
+    #[cfg(target_arch = "x86_64")]
+    type Sse2 = Vectors<__m128, 4>;
+    #[cfg(target_arch = "x86_64")]
+    type Avx2 = Vectors<__m256, 2>;
+    #[cfg(target_arch = "x86_64")]
+    type Avx512 = Vectors<__m512, 1>;
+}
+
+impl Element for f64 {
+    #[inline(always)]
+    fn from_single(value: f32) -> Self {
+        value as f64
+    }
+
+    fn to_single(self) -> f32 {
+        self as f32
+    }
+
+    type Scalar = Scalar<f64>;
+    #[cfg(target_arch = "x86_64")]
+    type Sse2 = Vectors<__m128d, 8>;
+    #[cfg(target_arch = "x86_64")]
+    type Avx2 = Vectors<__m256d, 4>;
+    #[cfg(target_arch = "x86_64")]
+    type Avx512 = Vectors<__m512d, 2>;
+}
+
+/// The types the impulse spectra can be stored as for sums in `T`. The half-precision types halve
+/// the memory and bandwidth of the spectra, and are widened to `T` as they are loaded.
+pub trait Stored<T>: Copy + Default + Send + Sync + 'static {
+    fn narrow(value: T) -> Self;
+    fn widen(self) -> T;
+}
+
+impl<T: Element> Stored<T> for T {
+    fn narrow(value: T) -> Self {
+        value
+    }
+
+    #[inline(always)]
+    fn widen(self) -> T {
+        self
+    }
+}
+
+impl<T: Element> Stored<T> for f16 {
+    fn narrow(value: T) -> Self {
+        f16::from_f32(value.to_single())
+    }
+
+    /// Written out instead of using the F16C instructions, but in a form that vectorizes. The
+    /// magnitude bits are moved into place, and multiplying with the difference of the exponent
+    /// biases fixes up the exponent of normal and subnormal numbers alike.
+    #[inline(always)]
+    fn widen(self) -> T {
+        let bits = self.to_bits() as u32;
+        // 2^112
+        let magnitude = f32::from_bits((bits & 0x7fff) << 13) * f32::from_bits(0x7780_0000);
+        let mut bits = magnitude.to_bits() | (bits & 0x8000) << 16;
+        if magnitude >= 65536.0 {
+            // Infinity and NaN
+            bits |= 0x7f80_0000;
+        }
+        T::from_single(f32::from_bits(bits))
+    }
+}
+
+impl<T: Element> Stored<T> for bf16 {
+    fn narrow(value: T) -> Self {
+        bf16::from_f32(value.to_single())
+    }
+
+    #[inline(always)]
+    fn widen(self) -> T {
+        T::from_single(f32::from_bits((self.to_bits() as u32) << 16))
+    }
+}
+
+/// Adds the products of the `impulses` in `partitions` with their input spectra to the single
+/// spectrum in `output`. Partition `j` is multiplied with input spectrum `(offset + j) % n`, where
+/// `n` is the number of input spectra. The partitions are summed from the last one down, so every
+/// bin is added up in the same order as by repeated calls with consecutive ranges.
+pub fn accumulate<T: Element, H: Stored<T>>(
+    output: &mut SplitSpectra<T>,
+    inputs: &SplitSpectra<T>,
+    offset: usize,
+    impulses: &SplitSpectra<H>,
+    partitions: Range<usize>,
+) {
+    accumulate_with(
+        InstructionSet::detect(),
+        output,
+        inputs,
+        offset,
+        impulses,
+        partitions,
+    );
+}
+
+/// [`accumulate`] with a specific instruction set. Panics if this CPU does not support it.
+pub fn accumulate_with<T: Element, H: Stored<T>>(
+    instruction_set: InstructionSet,
+    output: &mut SplitSpectra<T>,
+    inputs: &SplitSpectra<T>,
+    offset: usize,
+    impulses: &SplitSpectra<H>,
+    partitions: Range<usize>,
+) {
+    assert_eq!(output.num_spectra, 1);
+    assert_eq!(inputs.num_bins, impulses.num_bins);
+    assert_eq!(output.num_bins, impulses.num_bins);
+    assert!(partitions.end <= impulses.num_spectra);
+    // Checking is cheap next to the work, the features are only detected once
+    assert!(instruction_set.is_supported());
+
+    if partitions.is_empty() {
+        return;
+    }
+
+    let last = (offset + partitions.end - 1) % inputs.num_spectra;
+    let args = (output, inputs, last, impulses, partitions);
+
+    // SAFETY: The CPU supports the instruction set, see above
+    unsafe {
+        match instruction_set {
+            InstructionSet::Scalar => accumulate_blocks::<T, H, T::Scalar>(args),
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Sse2 => accumulate_sse2(args),
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Avx2 => accumulate_avx2(args),
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Avx512 => accumulate_avx512(args),
+        }
+    }
+}
+
+type Args<'a, T, H> = (
+    &'a mut SplitSpectra<T>,
+    &'a SplitSpectra<T>,
+    usize,
+    &'a SplitSpectra<H>,
+    Range<usize>,
+);
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn accumulate_sse2<T: Element, H: Stored<T>>(args: Args<T, H>) {
+    accumulate_blocks::<T, H, T::Sse2>(args)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn accumulate_avx2<T: Element, H: Stored<T>>(args: Args<T, H>) {
+    accumulate_blocks::<T, H, T::Avx2>(args)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn accumulate_avx512<T: Element, H: Stored<T>>(args: Args<T, H>) {
+    accumulate_blocks::<T, H, T::Avx512>(args)
+}
+
+/// Number of groups of bins that are summed at the same time.
+const GROUPS_PER_PASS: usize = 4;
+
+/// The body shared by all instruction sets. It is inlined into functions that enable the target
+/// features of `B`, so the intrinsics are compiled for them.
+#[inline(always)]
+unsafe fn accumulate_blocks<T: Element, H: Stored<T>, B: Block<T>>(args: Args<T, H>) {
+    let (output, inputs, last, impulses, partitions) = args;
+
+    // Several groups at once keep enough independent sums in flight to hide the latency of the
+    // additions
+    let num_groups = output.num_groups();
+    let mut group = 0;
+    while group + GROUPS_PER_PASS <= num_groups {
+        accumulate_groups::<T, H, B, GROUPS_PER_PASS>(
+            output,
+            inputs,
+            last,
+            impulses,
+            &partitions,
+            group,
+        );
+        group += GROUPS_PER_PASS;
+    }
+    while group < num_groups {
+        accumulate_groups::<T, H, B, 1>(output, inputs, last, impulses, &partitions, group);
+        group += 1;
+    }
+}
+
+#[inline(always)]
+unsafe fn accumulate_groups<T: Element, H: Stored<T>, B: Block<T>, const N: usize>(
+    output: &mut SplitSpectra<T>,
+    inputs: &SplitSpectra<T>,
+    last: usize,
+    impulses: &SplitSpectra<H>,
+    partitions: &Range<usize>,
+    first_group: usize,
+) {
+    let mut re: [B; N] =
+        std::array::from_fn(|g| B::load(&output.re[output.block(first_group + g, 0)]));
+    let mut im: [B; N] =
+        std::array::from_fn(|g| B::load(&output.im[output.block(first_group + g, 0)]));
+
+    let mut index = last;
+    for j in partitions.clone().rev() {
+        for g in 0..N {
+            let x = inputs.block(first_group + g, index);
+            let h = impulses.block(first_group + g, j);
+            let (x_re, x_im) = (B::load(&inputs.re[x.clone()]), B::load(&inputs.im[x]));
+            let (h_re, h_im) = (
+                B::load_widened(&impulses.re[h.clone()]),
+                B::load_widened(&impulses.im[h]),
+            );
+
+            re[g] = re[g].mul_add(x_re, h_re).mul_sub(x_im, h_im);
+            im[g] = im[g].mul_add(x_re, h_im).mul_add(x_im, h_re);
+        }
+
+        index = if index > 0 {
+            index - 1
+        } else {
+            inputs.num_spectra - 1
+        };
+    }
+
+    for g in 0..N {
+        let block = output.block(first_group + g, 0);
+        re[g].store(&mut output.re[block.clone()]);
+        im[g].store(&mut output.im[block]);
+    }
+}
+
+/// [`LANES`] values in the registers of an instruction set. The methods may only be called when
+/// the CPU supports that instruction set.
+pub trait Block<T>: Copy {
+    /// Loads the first [`LANES`] values of `src`.
+    unsafe fn load(src: &[T]) -> Self;
+    /// Loads the first [`LANES`] values of `src` widened to `T`.
+    #[inline(always)]
+    unsafe fn load_widened<S: Stored<T>>(src: &[S]) -> Self {
+        let src = &src[..LANES];
+        Self::load(&std::array::from_fn::<T, LANES, _>(|i| src[i].widen()))
+    }
+    unsafe fn store(self, dst: &mut [T]);
+    /// `self + a * b`
+    unsafe fn mul_add(self, a: Self, b: Self) -> Self;
+    /// `self - a * b`
+    unsafe fn mul_sub(self, a: Self, b: Self) -> Self;
+}
+
+#[derive(Clone, Copy)]
+pub struct Scalar<T>([T; LANES]);
+
+impl<T> Block<T> for Scalar<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+{
+    #[inline(always)]
+    unsafe fn load(src: &[T]) -> Self {
+        Scalar(std::array::from_fn(|i| src[i]))
+    }
+
+    #[inline(always)]
+    unsafe fn store(self, dst: &mut [T]) {
+        dst[..LANES].copy_from_slice(&self.0);
+    }
+
+    #[inline(always)]
+    unsafe fn mul_add(self, a: Self, b: Self) -> Self {
+        Scalar(std::array::from_fn(|i| self.0[i] + a.0[i] * b.0[i]))
+    }
+
+    #[inline(always)]
+    unsafe fn mul_sub(self, a: Self, b: Self) -> Self {
+        Scalar(std::array::from_fn(|i| self.0[i] - a.0[i] * b.0[i]))
+    }
+}
+
+/// `N` vector registers of type `V`, which hold [`LANES`] values together.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy)]
+pub struct Vectors<V, const N: usize>([V; N]);
+
+/// Implements [`Block`] for [`Vectors`] of `$vector` holding `$width` values of `$element` each.
+/// The `mul_add` arm takes a fused multiply-add and its negated version, the other arm separate
+/// multiplications and additions.
+#[cfg(target_arch = "x86_64")]
+macro_rules! impl_block {
+    ($element:ty, $vector:ty, $width:literal, $load:ident, $store:ident, fused: $fmadd:ident, $fnmadd:ident) => {
+        impl_block!(@impl $element, $vector, $width, $load, $store,
+            |s, a, b| $fmadd(a, b, s),
+            |s, a, b| $fnmadd(a, b, s));
+    };
+    ($element:ty, $vector:ty, $width:literal, $load:ident, $store:ident, separate: $add:ident, $sub:ident, $mul:ident) => {
+        impl_block!(@impl $element, $vector, $width, $load, $store,
+            |s, a, b| $add(s, $mul(a, b)),
+            |s, a, b| $sub(s, $mul(a, b)));
+    };
+    (@impl $element:ty, $vector:ty, $width:literal, $load:ident, $store:ident, $mul_add:expr, $mul_sub:expr) => {
+        impl Block<$element> for Vectors<$vector, { LANES / $width }> {
+            #[inline(always)]
+            unsafe fn load(src: &[$element]) -> Self {
+                let src = &src[..LANES];
+                Vectors(std::array::from_fn(|i| $load(src[$width * i..].as_ptr())))
+            }
+
+            #[inline(always)]
+            unsafe fn store(self, dst: &mut [$element]) {
+                let dst = &mut dst[..LANES];
+                for (i, v) in self.0.into_iter().enumerate() {
+                    $store(dst[$width * i..].as_mut_ptr(), v);
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn mul_add(self, a: Self, b: Self) -> Self {
+                let mul_add = $mul_add;
+                Vectors(std::array::from_fn(|i| mul_add(self.0[i], a.0[i], b.0[i])))
+            }
+
+            #[inline(always)]
+            unsafe fn mul_sub(self, a: Self, b: Self) -> Self {
+                let mul_sub = $mul_sub;
+                Vectors(std::array::from_fn(|i| mul_sub(self.0[i], a.0[i], b.0[i])))
+            }
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+impl_block!(f32, __m128, 4, _mm_loadu_ps, _mm_storeu_ps, separate: _mm_add_ps, _mm_sub_ps, _mm_mul_ps);
+#[cfg(target_arch = "x86_64")]
+impl_block!(f32, __m256, 8, _mm256_loadu_ps, _mm256_storeu_ps, fused: _mm256_fmadd_ps, _mm256_fnmadd_ps);
+#[cfg(target_arch = "x86_64")]
+impl_block!(f32, __m512, 16, _mm512_loadu_ps, _mm512_storeu_ps, fused: _mm512_fmadd_ps, _mm512_fnmadd_ps);
+#[cfg(target_arch = "x86_64")]
+impl_block!(f64, __m128d, 2, _mm_loadu_pd, _mm_storeu_pd, separate: _mm_add_pd, _mm_sub_pd, _mm_mul_pd);
+#[cfg(target_arch = "x86_64")]
+impl_block!(f64, __m256d, 4, _mm256_loadu_pd, _mm256_storeu_pd, fused: _mm256_fmadd_pd, _mm256_fnmadd_pd);
+#[cfg(target_arch = "x86_64")]
+impl_block!(f64, __m512d, 8, _mm512_loadu_pd, _mm512_storeu_pd, fused: _mm512_fmadd_pd, _mm512_fnmadd_pd);
+
+#[cfg(test)]
+mod tests {
+    use super::{accumulate_with, InstructionSet, SplitSpectra, Stored};
+    use crate::allocator::{AlignedBuffer, Arena};
+    use crate::sample::Sample;
+    use half::{bf16, f16};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use rustfft::num_complex::Complex;
+
+    type Spectrum<T> = AlignedBuffer<Complex<T>>;
+
+    fn zeros<T: Sample>(num_bins: usize) -> Spectrum<T> {
+        AlignedBuffer::new(num_bins, Complex::default())
+    }
+
+    fn spectra<T: Copy + Default>(num_bins: usize, num_spectra: usize) -> SplitSpectra<T> {
+        let mut arena = Arena::new(SplitSpectra::<T>::arena_size(num_bins, num_spectra));
+        SplitSpectra::new_in(&mut arena, num_bins, num_spectra)
+    }
+
+    struct Partitions<T: Sample> {
+        inputs: Vec<Spectrum<T>>,
+        impulses: Vec<Spectrum<T>>,
+    }
+
+    /// Random spectra of real signals, so the first and last bins are real.
+    fn random_partitions<T: Sample>(num_bins: usize, num_partitions: usize) -> Partitions<T> {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut random = || T::from_f64(rng.gen_range(-1.0..1.0)).unwrap();
+        let mut spectrum = || {
+            let mut spectrum = zeros(num_bins);
+            for bin in spectrum.iter_mut() {
+                *bin = Complex::new(random(), random());
+            }
+            spectrum[0].im = T::zero();
+            spectrum[num_bins - 1].im = T::zero();
+            spectrum
+        };
+
+        Partitions {
+            inputs: (0..num_partitions).map(|_| spectrum()).collect(),
+            impulses: (0..num_partitions).map(|_| spectrum()).collect(),
+        }
+    }
+
+    fn split<T: Sample, H: Stored<T>>(interleaved: &[Spectrum<T>]) -> SplitSpectra<H> {
+        let mut split = spectra(interleaved[0].len(), interleaved.len());
+        for (i, spectrum) in interleaved.iter().enumerate() {
+            split.store(i, spectrum);
+        }
+        split
+    }
+
+    /// Checks every instruction set with the impulses stored as `H`.
+    fn matches_complex_multiplication<T: Sample, H: Stored<T>>(tolerance: T) {
+        const NUM_BINS: usize = 513;
+        const NUM_PARTITIONS: usize = 7;
+        const OFFSET: usize = 3;
+
+        let mut partitions = random_partitions::<T>(NUM_BINS, NUM_PARTITIONS);
+        // The expected sums use the impulses as they are seen by the kernel
+        for bin in partitions.impulses.iter_mut().flat_map(|s| s.iter_mut()) {
+            *bin = Complex::new(H::narrow(bin.re).widen(), H::narrow(bin.im).widen());
+        }
+
+        let mut expected = zeros::<T>(NUM_BINS);
+        for j in (0..NUM_PARTITIONS).rev() {
+            let input = &partitions.inputs[(OFFSET + j) % NUM_PARTITIONS];
+            for ((e, x), h) in expected
+                .iter_mut()
+                .zip(input.iter())
+                .zip(partitions.impulses[j].iter())
+            {
+                *e = *e + *x * *h;
+            }
+        }
+
+        let inputs = split::<T, T>(&partitions.inputs);
+        let impulses = split::<T, H>(&partitions.impulses);
+
+        let instruction_sets = [
+            InstructionSet::Scalar,
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Sse2,
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Avx2,
+            #[cfg(target_arch = "x86_64")]
+            InstructionSet::Avx512,
+        ];
+        for instruction_set in instruction_sets.into_iter().filter(|s| s.is_supported()) {
+            // Accumulating in two steps adds up the partitions in the same order
+            let mut output = spectra(NUM_BINS, 1);
+            for range in [4..NUM_PARTITIONS, 0..4] {
+                accumulate_with(
+                    instruction_set,
+                    &mut output,
+                    &inputs,
+                    OFFSET,
+                    &impulses,
+                    range,
+                );
+            }
+
+            let mut result = zeros(NUM_BINS);
+            output.load(0, &mut result);
+            for (r, e) in result.iter().zip(expected.iter()) {
+                assert!(
+                    (r - e).norm() < tolerance,
+                    "{r:?} != {e:?} with {instruction_set:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matches_complex_multiplication_f32() {
+        matches_complex_multiplication::<f32, f32>(1e-5);
+    }
+
+    #[test]
+    fn matches_complex_multiplication_f64() {
+        matches_complex_multiplication::<f64, f64>(1e-12);
+    }
+
+    #[test]
+    fn matches_complex_multiplication_half_precision() {
+        matches_complex_multiplication::<f32, f16>(1e-5);
+        matches_complex_multiplication::<f32, bf16>(1e-5);
+        matches_complex_multiplication::<f64, f16>(1e-12);
+    }
+
+    #[test]
+    fn widens_every_f16() {
+        for bits in 0..=u16::MAX {
+            let value = f16::from_bits(bits);
+            let widened: f32 = value.widen();
+            if value.is_nan() {
+                assert!(widened.is_nan());
+            } else {
+                assert_eq!(widened.to_bits(), value.to_f32().to_bits(), "{value}");
+            }
+        }
+    }
+
+    /// Benchmarks against the interleaved kernel that walks one partition at a time.
+    #[cfg(feature = "nightly-simd")]
+    mod benches {
+        extern crate test;
+
+        use super::super::{accumulate, Stored};
+        use super::{random_partitions, spectra, split, zeros};
+        use half::{bf16, f16};
+        use rustfft::num_complex::Complex;
+        use std::mem::transmute;
+        use std::simd::prelude::*;
+        use test::Bencher;
+
+        /// The interleaved kernel that walks one partition at a time, kept as a reference.
+        fn convolve_and_accumulate(
+            input: &[Complex<f32>],
+            impulse: &[Complex<f32>],
+            output: &mut [Complex<f32>],
+        ) {
+            let (_, impulses, impulses_suffix) = unsafe {
+                let impulse_ptr: *const f32 = transmute(impulse.as_ptr());
+                std::slice::from_raw_parts(impulse_ptr, impulse.len() * 2).as_simd()
+            };
+            let (_, inputs, inputs_suffix) = unsafe {
+                let input_ptr: *const f32 = transmute(input.as_ptr());
+                std::slice::from_raw_parts(input_ptr, input.len() * 2).as_simd()
+            };
+            let (_, outputs, outputs_suffix) = unsafe {
+                let output_ptr: *mut f32 = transmute(output.as_ptr());
+                std::slice::from_raw_parts_mut(output_ptr, output.len() * 2).as_simd_mut()
+            };
+
+            for i in 0..inputs.len() {
+                // [R0, I0, R1, I1, R2, I2, R3, I3]
+                let impulse_v: Simd<f32, 8> = impulses[i];
+                // [r0, i0, r1, i1, r2, i2, r3, i3]
+                let input_v: Simd<f32, 8> = inputs[i];
+
+                // [r0R0, i0I0, r1R1, i1I1, r2R2, i2I2, r3R3, i3I3]
+                let reals = input_v * impulse_v;
+                let reals_n = -reals;
+
+                // [r0R0, -i0I0, r1R1, -i1I1, r2R2, -i2I2, r3R3, -i3I3]
+                let reals = core::simd::simd_swizzle!(reals, reals_n, [0, 9, 2, 11, 4, 13, 6, 15]);
+                // [r0I0, i0R0, r1I1, i1R1, r2I2, i2R2, r3I3, i3R3]
+                let imaginary =
+                    input_v * core::simd::simd_swizzle!(impulse_v, [1, 0, 3, 2, 5, 4, 7, 6]);
+
+                // [r0R0, r0I0, r1R1, r1I1, r2R2, r2I2, r3R3, r3I3]
+                let first_half =
+                    core::simd::simd_swizzle!(reals, imaginary, [0, 8, 2, 10, 4, 12, 6, 14]);
+                // [-i0I0, i0R0, -i1I1, i1R1, -i2I2, i2R2, -i3I3, i3R3]
+                let second_half =
+                    core::simd::simd_swizzle!(reals, imaginary, [1, 9, 3, 11, 5, 13, 7, 15]);
+
+                outputs[i] += first_half + second_half;
+            }
+
+            let input = inputs_suffix.iter();
+            let impulse = impulses_suffix.iter();
+            let output = outputs_suffix.iter_mut();
+
+            for ((i, im), o) in input.zip(impulse).zip(output) {
+                *o += *i * *im;
+            }
+        }
+
+        fn bench_interleaved(b: &mut Bencher, num_bins: usize, num_partitions: usize) {
+            let partitions = random_partitions::<f32>(num_bins, num_partitions);
+            let mut output = zeros(num_bins);
+
+            b.iter(|| {
+                output.fill(Complex::new(0.0, 0.0));
+                for (input, impulse) in partitions.inputs.iter().zip(&partitions.impulses) {
+                    convolve_and_accumulate(input, impulse, &mut output);
+                }
+                test::black_box(&output);
+            });
+        }
+
+        fn bench_split<H: Stored<f32>>(b: &mut Bencher, num_bins: usize, num_partitions: usize) {
+            let partitions = random_partitions::<f32>(num_bins, num_partitions);
+            let inputs = split::<f32, f32>(&partitions.inputs);
+            let impulses = split::<f32, H>(&partitions.impulses);
+            let mut output = spectra(num_bins, 1);
+
+            b.iter(|| {
+                output.fill_zero();
+                accumulate(&mut output, &inputs, 0, &impulses, 0..num_partitions);
+                test::black_box(&output);
+            });
+        }
+
+        // An early stage that stays in the cache
+        #[bench]
+        fn interleaved_kernel_short(b: &mut Bencher) {
+            bench_interleaved(b, 257, 24);
+        }
+
+        #[bench]
+        fn split_kernel_short(b: &mut Bencher) {
+            bench_split::<f32>(b, 257, 24);
+        }
+
+        // A 4 second impulse response at 48 kHz split into 1024 sample partitions
+        #[bench]
+        fn interleaved_kernel_long(b: &mut Bencher) {
+            bench_interleaved(b, 1025, 188);
+        }
+
+        #[bench]
+        fn split_kernel_long(b: &mut Bencher) {
+            bench_split::<f32>(b, 1025, 188);
+        }
+
+        #[bench]
+        fn split_kernel_long_f16(b: &mut Bencher) {
+            bench_split::<f16>(b, 1025, 188);
+        }
+
+        #[bench]
+        fn split_kernel_long_bf16(b: &mut Bencher) {
+            bench_split::<bf16>(b, 1025, 188);
+        }
+    }
+}
@@ -1,33 +1,71 @@
-#![feature(portable_simd)]
-#![feature(allocator_api)]
+#![cfg_attr(feature = "nightly-simd", feature(portable_simd))]
+#![cfg_attr(all(test, feature = "nightly-simd"), feature(test))]
 
 use nih_plug::prelude::*;
 use vizia_plug::ViziaState;
+use std::ops::ControlFlow;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::PoisonError;
 
 mod allocator;
 mod browser;
 mod convolution;
+mod decoder;
+mod delay;
 mod editor;
 mod fft;
+mod kernel;
+mod loader;
+mod mailbox;
 mod plugin;
+mod pool;
+mod resample;
+mod sample;
 
-use convolution::ConvolutionEngine;
+use convolution::{ConvolutionEngine, EngineOptions, Latency, Routing, SpectrumStorage};
+use decoder::Limits;
+use delay::DelayLine;
+use loader::{IrLoader, LoadError, LoadStage, LoadedIr};
+use plugin::EngineSet;
+use pool::ThreadPool;
 
-enum Message {
-    Impulse(Vec<u8>),
-    Engine(Vec<ConvolutionEngine>),
-}
+/// The length of the fade when the plugin is bypassed.
+const BYPASS_FADE_MS: f32 = 10.0;
+
+/// The part of a truncated impulse response that is faded out, so it doesn't end in a click.
+const TRUNCATION_FADE: f32 = 0.1;
+
+/// The most threads that prepare an impulse response at once.
+const MAX_PREPARATION_THREADS: usize = 8;
 
 /// This is mostly identical to the gain example, minus some fluff, and with a GUI.
 pub struct ConvolutionReverb {
     params: Arc<PlugParams>,
     sample_rate: u32,
+    num_channels: usize,
+    max_block_size: usize,
+    /// The configuration the most recently requested engines are built for.
+    engine_config: EngineConfig,
+    /// Shared with the editor and the background thread.
+    loader: Arc<IrLoader>,
+    /// The latest generation of the loader that engines were requested for.
+    requested_generation: u64,
 
     internal: plugin::AudioPlugin,
-    tx: crossbeam::channel::Sender<Message>,
-    rx: crossbeam::channel::Receiver<Message>,
+    drys: [Vec<f32>; 2],
+    /// Delays the dry signal by the latency of the engines.
+    dry_delays: [DelayLine; 2],
+    /// The input of the engines, which is faded out when only the input is bypassed.
+    sends: [Vec<f32>; 2],
+    gains: Vec<f32>,
+    mixes: Vec<f32>,
+    /// Fades between the processed and the bypassed signal, 1.0 is fully bypassed.
+    bypass: Smoother<f32>,
+    /// Whether `bypass` is fading to the bypassed signal. Its target is only set when the parameter
+    /// changes, since setting the same target again restarts the fade.
+    bypassed: bool,
+    bypasses: Vec<f32>,
 
     /// Needed to normalize the peak meter's response based on the sample rate.
     peak_meter_decay_weight: f32,
@@ -50,6 +88,56 @@ struct PlugParams {
     #[id = "bypassed"]
     pub bypassed: BoolParam,
 
+    /// Only bypass the input of the reverb, so the tail rings out.
+    #[id = "bypass-input-only"]
+    pub bypass_input_only: BoolParam,
+
+    /// The length of the crossfade between the old and the new impulse response in milliseconds.
+    #[id = "crossfade"]
+    pub crossfade: FloatParam,
+
+    #[id = "routing"]
+    pub routing: EnumParam<RoutingMode>,
+
+    #[id = "latency"]
+    pub latency: EnumParam<LatencyMode>,
+
+    /// The precision the impulse response is convolved at. Switching cuts over to the new engines
+    /// without a crossfade.
+    #[id = "quality"]
+    pub quality: EnumParam<Quality>,
+
+    #[id = "ir-storage"]
+    pub ir_storage: EnumParam<IrStorage>,
+
+    /// Impulse responses longer than this in seconds are rejected, or truncated if
+    /// `truncate_long_irs` is on.
+    #[id = "max-ir-length"]
+    pub max_ir_length: FloatParam,
+
+    /// The memory an impulse response may take up in MiB, both while decoding it and in the
+    /// engines.
+    #[id = "memory-budget"]
+    pub memory_budget: IntParam,
+
+    #[id = "truncate-long-irs"]
+    pub truncate_long_irs: BoolParam,
+
+    /// Computes the late, long partitions of the impulse response on a separate thread, which gets
+    /// as much time for a block as the delay of its partitions allows. When rendering offline, the
+    /// audio thread waits for it, so the output is the same as without it.
+    #[id = "threaded-tail"]
+    pub threaded_tail: BoolParam,
+
+    #[id = "swap-channels"]
+    pub swap_channels: BoolParam,
+
+    #[id = "invert-left"]
+    pub invert_left: BoolParam,
+
+    #[id = "invert-right"]
+    pub invert_right: BoolParam,
+
     /// The editor state, saved together with the parameter state so the custom scaling can be
     /// restored.
     #[persist = "editor-state"]
@@ -59,23 +147,184 @@ struct PlugParams {
     impulse: Arc<Mutex<Vec<u8>>>,
 }
 
+/// How the channels are routed through the impulse response. `Auto` picks the routing from the
+/// number of channels in the impulse response and the channel layout.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingMode {
+    #[name = "Auto"]
+    Auto,
+    #[name = "Stereo"]
+    Stereo,
+    #[name = "Mono to Stereo"]
+    MonoToStereo,
+    #[name = "Stereo to Mono"]
+    StereoToMono,
+    #[name = "True Stereo"]
+    TrueStereo,
+}
+
+impl RoutingMode {
+    fn preferred(self) -> Option<Routing> {
+        match self {
+            RoutingMode::Auto => None,
+            RoutingMode::Stereo => Some(Routing::Parallel),
+            RoutingMode::MonoToStereo => Some(Routing::MonoToStereo),
+            RoutingMode::StereoToMono => Some(Routing::StereoToMono),
+            RoutingMode::TrueStereo => Some(Routing::TrueStereo),
+        }
+    }
+}
+
+/// The trade-off between latency and CPU usage.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyMode {
+    /// No latency, the first partition is convolved in the time domain.
+    #[name = "Low"]
+    Low,
+    /// One host buffer of latency.
+    #[name = "Normal"]
+    Normal,
+    /// Four host buffers of latency for the lowest CPU usage.
+    #[name = "High"]
+    High,
+}
+
+impl LatencyMode {
+    /// The largest partition size of the first stage of the engines.
+    const MAX_BLOCK_SIZE: usize = 8192;
+    /// The largest latency of any engines, whatever the buffer size they were built for.
+    const MAX_LATENCY: usize = 4 * Self::MAX_BLOCK_SIZE;
+
+    /// The partition size and latency of the engines for the host's maximum buffer size.
+    fn engine_settings(self, max_block_size: usize) -> (usize, Latency) {
+        let block_size = usize::clamp(max_block_size.next_power_of_two(), 32, Self::MAX_BLOCK_SIZE);
+        match self {
+            LatencyMode::Low => (block_size, Latency::Zero),
+            LatencyMode::Normal => (block_size, Latency::Partition),
+            LatencyMode::High => (4 * block_size, Latency::Partition),
+        }
+    }
+}
+
+/// The floating point precision of the convolution.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    #[name = "32-bit"]
+    Single,
+    /// Keeps the rounding error of long impulse responses down, at about twice the CPU usage.
+    #[name = "64-bit"]
+    Double,
+}
+
+/// How compactly the spectra of the impulse response are stored, see [`SpectrumStorage`].
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrStorage {
+    #[name = "Full"]
+    Full,
+    /// Half the memory at an SNR of about 74 dB.
+    #[name = "16-bit"]
+    Half,
+    /// Half the memory at an SNR of about 56 dB, but the least CPU usage.
+    #[name = "bfloat16"]
+    BFloat,
+}
+
+impl IrStorage {
+    fn spectrum_storage(self) -> SpectrumStorage {
+        match self {
+            IrStorage::Full => SpectrumStorage::Full,
+            IrStorage::Half => SpectrumStorage::F16,
+            IrStorage::BFloat => SpectrumStorage::Bf16,
+        }
+    }
+}
+
+/// Everything the engines are built for apart from the impulse response itself. The engines are
+/// rebuilt when this changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EngineConfig {
+    sample_rate: u32,
+    num_channels: usize,
+    max_block_size: usize,
+    routing: RoutingMode,
+    latency: LatencyMode,
+    quality: Quality,
+    ir_storage: IrStorage,
+    max_ir_length: f32,
+    /// In bytes.
+    memory_budget: usize,
+    truncate_long_irs: bool,
+    threaded_tail: bool,
+}
+
+impl EngineConfig {
+    fn engine_options(&self) -> EngineOptions {
+        let (max_block_size, latency) = self.latency.engine_settings(self.max_block_size);
+        EngineOptions {
+            max_block_size,
+            latency,
+            threaded: self.threaded_tail,
+            spectrum_storage: self.ir_storage.spectrum_storage(),
+        }
+    }
+
+    /// The most memory building `num_engines` engines takes for an impulse response with
+    /// `num_channels` decoded channels of `frames` samples, which are kept until the engines are
+    /// built.
+    fn peak_memory(&self, num_channels: usize, num_engines: usize, frames: usize) -> usize {
+        let options = self.engine_options();
+        let engine = match self.quality {
+            Quality::Single => ConvolutionEngine::<f32>::build_footprint_for(frames, options),
+            Quality::Double => ConvolutionEngine::<f64>::build_footprint_for(frames, options),
+        };
+        num_channels * frames * size_of::<f32>() + num_engines * engine
+    }
+}
+
 #[derive(Debug)]
 pub enum BackgroundTask {
-    OpenImpulse(Vec<u8>),
-    ProcessImpulse(Vec<u8>, u32),
+    /// Builds the engines for a generation of the [`IrLoader`], unless it is stale by then.
+    BuildEngines(u64, EngineConfig),
+    /// Engines that were swapped out on the audio thread, which must not deallocate them.
+    DropEngines(EngineSet),
+    /// Stale engines the audio thread took from the [`IrLoader`], or the box of the engines it
+    /// swapped in.
+    DropLoaded(Box<LoadedIr>),
 }
 
 impl Default for ConvolutionReverb {
     fn default() -> Self {
         let plugin = plugin::AudioPlugin::new();
-        let (tx, rx) = crossbeam::channel::bounded(1024);
         Self {
             params: Arc::new(PlugParams::default()),
             sample_rate: 0,
+            num_channels: 0,
+            max_block_size: 0,
+            engine_config: EngineConfig {
+                sample_rate: 0,
+                num_channels: 0,
+                max_block_size: 0,
+                routing: RoutingMode::Auto,
+                latency: LatencyMode::Low,
+                quality: Quality::Single,
+                ir_storage: IrStorage::Full,
+                max_ir_length: 0.0,
+                memory_budget: 0,
+                truncate_long_irs: false,
+                threaded_tail: false,
+            },
+            loader: Arc::new(IrLoader::default()),
+            requested_generation: 0,
 
             internal: plugin,
-            tx,
-            rx,
+            drys: [Vec::new(), Vec::new()],
+            dry_delays: [DelayLine::new(0), DelayLine::new(0)],
+            sends: [Vec::new(), Vec::new()],
+            gains: Vec::new(),
+            mixes: Vec::new(),
+            bypass: Smoother::new(SmoothingStyle::Linear(BYPASS_FADE_MS)),
+            bypassed: false,
+            bypasses: Vec::new(),
 
             peak_meter_decay_weight: 1.0,
         }
@@ -117,6 +366,42 @@ impl Default for PlugParams {
                 .with_unit(" %"),
 
             bypassed: BoolParam::new("Bypassed", false),
+            bypass_input_only: BoolParam::new("Bypass Input Only", false),
+            crossfade: FloatParam::new(
+                "IR Crossfade",
+                200.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 2000.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(0))
+            .with_unit(" ms"),
+            routing: EnumParam::new("Routing", RoutingMode::Auto),
+            latency: EnumParam::new("Latency", LatencyMode::Low),
+            quality: EnumParam::new("Quality", Quality::Single),
+            ir_storage: EnumParam::new("IR Storage", IrStorage::Full),
+            max_ir_length: FloatParam::new(
+                "Max IR Length",
+                30.0,
+                FloatRange::Linear {
+                    min: 1.0,
+                    max: 120.0,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(0))
+            .with_unit(" s"),
+            memory_budget: IntParam::new(
+                "Memory Budget",
+                512,
+                IntRange::Linear { min: 16, max: 4096 },
+            )
+            .with_unit(" MiB"),
+            truncate_long_irs: BoolParam::new("Truncate Long IRs", false),
+            threaded_tail: BoolParam::new("Threaded Tail", false),
+            swap_channels: BoolParam::new("Swap Channels", false),
+            invert_left: BoolParam::new("Invert Left", false),
+            invert_right: BoolParam::new("Invert Right", false),
             editor_state: editor::default_state(),
             impulse: Arc::new(Mutex::new(Vec::default())),
         }
@@ -152,63 +437,133 @@ impl Plugin for ConvolutionReverb {
         self.params.clone()
     }
 
-    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         editor::create(
             self.params.clone(),
             self.params.editor_state.clone(),
-            async_executor,
+            self.loader.clone(),
         )
     }
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
         context: &mut impl InitContext<Self>,
     ) -> bool {
         // TODO: How do you tie this exponential decay to an actual time span?
         self.peak_meter_decay_weight = 0.9992f32.powf(44_100.0 / buffer_config.sample_rate);
         self.sample_rate = buffer_config.sample_rate as u32;
+        self.num_channels = audio_io_layout
+            .main_output_channels
+            .map_or(0, |c| c.get() as usize);
+        self.max_block_size = buffer_config.max_buffer_size as usize;
+        self.engine_config = self.current_engine_config();
+
+        self.internal
+            .initialize(buffer_config.sample_rate as usize, self.max_block_size);
+        self.internal
+            .set_offline(buffer_config.process_mode == ProcessMode::Offline);
+        for buffer in self.drys.iter_mut().chain(&mut self.sends) {
+            buffer.resize(self.max_block_size, 0.0);
+        }
+        self.gains.resize(self.max_block_size, 0.0);
+        self.mixes.resize(self.max_block_size, 0.0);
+        self.bypasses.resize(self.max_block_size, 0.0);
+        self.bypassed = self.params.bypassed.value();
+        self.bypass.reset(self.bypass_target());
+
+        // Enough for the latency of any engines, including the ones that survive reinitialization
+        // with a different buffer size, so switching engines doesn't allocate
+        self.dry_delays = [
+            DelayLine::new(LatencyMode::MAX_LATENCY),
+            DelayLine::new(LatencyMode::MAX_LATENCY),
+        ];
+        for delay in &mut self.dry_delays {
+            delay.set_delay(self.internal.latency(), 0);
+        }
 
-        {
-            let ir = self.params.impulse.lock().unwrap().clone();
-            if !ir.is_empty() {
-                context.execute(BackgroundTask::OpenImpulse(ir));
-            }
+        // The engines survive reinitialization
+        context.set_latency_samples(self.internal.latency() as u32);
+
+        // The restored impulse response is loaded again, and anything else is rebuilt for the new
+        // configuration
+        let ir = self
+            .params
+            .impulse
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if ir.is_empty() {
+            self.loader.invalidate();
+        } else {
+            self.loader.request(ir);
         }
 
         true
     }
 
+    fn reset(&mut self) {
+        self.internal.reset();
+        for delay in &mut self.dry_delays {
+            delay.reset();
+        }
+        self.bypassed = self.params.bypassed.value();
+        self.bypass.reset(self.bypass_target());
+    }
+
     fn process(
         &mut self,
         buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let message = self.rx.try_recv();
-        if let Ok(m) = message {
-            match m {
-                Message::Impulse(impulse_response) => {
-                    context.execute_background(BackgroundTask::ProcessImpulse(
-                        impulse_response,
-                        self.sample_rate,
-                    ));
-                }
-                Message::Engine(engines) => {
-                    self.internal.swap(engines);
+        let engine_config = self.current_engine_config();
+        if engine_config != self.engine_config {
+            self.engine_config = engine_config;
+            self.loader.invalidate();
+        }
+
+        // Every new generation is built once, with the current configuration
+        let generation = self.loader.generation();
+        if generation != self.requested_generation {
+            self.requested_generation = generation;
+            context.execute_background(BackgroundTask::BuildEngines(generation, engine_config));
+        }
+
+        if let Some(mut loaded) = self.loader.take() {
+            // The configuration may have changed since the engines were published
+            if self.loader.is_current(loaded.generation) {
+                if let Some(engines) = loaded.engines.take() {
+                    let crossfade_length = (self.params.crossfade.value() / 1000.0
+                        * self.sample_rate as f32)
+                        .round() as usize;
+                    self.internal
+                        .swap(engines, loaded.routing, crossfade_length);
+                    context.set_latency_samples(self.internal.latency() as u32);
+                    // The dry signal follows the latency of the wet signal it's mixed with
+                    for delay in &mut self.dry_delays {
+                        delay.set_delay(self.internal.latency(), crossfade_length);
+                    }
+                    self.loader.finish(loaded.generation);
                 }
             }
+            context.execute_background(BackgroundTask::DropLoaded(loaded));
         }
 
-        if self.params.bypassed.value() {
-            return ProcessStatus::Normal;
+        for engines in self.internal.take_retired() {
+            context.execute_background(BackgroundTask::DropEngines(engines));
         }
 
-        const MAX_BLOCK_LEN: usize = 1024;
-        let mut drys = [[0.0; MAX_BLOCK_LEN]; 2];
+        if self.params.bypassed.value() != self.bypassed {
+            self.bypassed = self.params.bypassed.value();
+            self.bypass
+                .set_target(self.sample_rate as f32, self.bypass_target());
+        }
+        // Without engines the wet signal is a copy of the input, which must not ring out
+        let ring_out = self.params.bypass_input_only.value() && self.internal.has_engines();
 
-        for channels in buffer.iter_blocks(MAX_BLOCK_LEN) {
+        for channels in buffer.iter_blocks(self.max_block_size) {
             let mut blocks: [&mut [f32]; 2] = [&mut [], &mut []];
 
             let num_channels = channels.1.channels();
@@ -216,80 +571,260 @@ impl Plugin for ConvolutionReverb {
             let mut iter = channels.1.into_iter();
             for i in 0..num_channels {
                 let channel = iter.next().unwrap();
-                drys[i][..num_samples].copy_from_slice(channel);
+                self.drys[i][..num_samples].copy_from_slice(channel);
                 blocks[i] = channel;
             }
 
-            self.internal.process(&drys, &mut blocks, &self.params);
+            let bypasses = &mut self.bypasses[..num_samples];
+            self.bypass.next_block(bypasses, num_samples);
+
+            for (send, dry) in self.sends.iter_mut().zip(&self.drys) {
+                if ring_out {
+                    for s in 0..num_samples {
+                        send[s] = dry[s] * (1.0 - bypasses[s]);
+                    }
+                } else {
+                    send[..num_samples].copy_from_slice(&dry[..num_samples]);
+                }
+            }
+
+            let inputs = [&self.sends[0][..num_samples], &self.sends[1][..num_samples]];
+            self.internal.process(
+                &inputs[..num_channels],
+                &mut blocks[..num_channels],
+                &self.params,
+            );
+
+            for (dry, delay) in self.drys.iter_mut().zip(&mut self.dry_delays) {
+                delay.process(&mut dry[..num_samples]);
+            }
 
-            let mut gains = [0.0_f32; MAX_BLOCK_LEN];
-            let mut mixes = [0.0_f32; MAX_BLOCK_LEN];
-            self.params
-                .gain
-                .smoothed
-                .next_block(&mut gains, num_samples);
-            self.params.mix.smoothed.next_block(&mut mixes, num_samples);
-            for (dry, wet) in drys.iter().zip(blocks.iter_mut()) {
+            let gains = &mut self.gains[..num_samples];
+            let mixes = &mut self.mixes[..num_samples];
+            self.params.gain.smoothed.next_block(gains, num_samples);
+            self.params.mix.smoothed.next_block(mixes, num_samples);
+            for (dry, wet) in self.drys.iter().zip(blocks.iter_mut()) {
                 for s in 0..num_samples {
-                    wet[s] = wet[s] * mixes[s] + dry[s] * (1.0 - mixes[s]);
-                    wet[s] *= gains[s];
+                    let reverb = wet[s] * mixes[s] * gains[s];
+                    let processed = reverb + dry[s] * (1.0 - mixes[s]) * gains[s];
+                    // The dry signal is delayed, so bypassing doesn't shift it in time
+                    let bypassed = if ring_out { dry[s] + reverb } else { dry[s] };
+                    wet[s] = processed * (1.0 - bypasses[s]) + bypassed * bypasses[s];
                 }
             }
         }
 
-        ProcessStatus::Normal
+        match self.internal.tail_length() {
+            0 => ProcessStatus::Normal,
+            tail_length => ProcessStatus::Tail(tail_length as u32),
+        }
     }
 
     fn task_executor(&mut self) -> TaskExecutor<Self> {
-        let tx = self.tx.clone();
+        let loader = self.loader.clone();
         let impulse = self.params.impulse.clone();
 
         Box::new(move |task| match task {
-            BackgroundTask::OpenImpulse(impulse_response) => {
-                tx.send(Message::Impulse(impulse_response)).unwrap();
-            }
-            BackgroundTask::ProcessImpulse(impulse_response, sample_rate) => {
-                let mut loader = symphonium::SymphoniumLoader::new();
-                let decoded_audio = loader
-                    .load_f32_from_source(
-                        Box::new(std::io::Cursor::new(impulse_response.clone())),
-                        None,
-                        Some(sample_rate),
-                        symphonium::ResampleQuality::High,
-                        None,
-                    )
-                    .expect("Failed to read samples");
-
-                let channels = decoded_audio.channels();
-                let sample_rate = decoded_audio.sample_rate;
-                let frames = decoded_audio.frames();
-
-                eprintln!("The number of channels in the impulse response: {channels}");
-                eprintln!("Sample rate of the impulse response: {sample_rate}");
-                eprintln!(
-                    "The number of samples per channel in the the impulse response: {frames}"
-                );
-
-                let length = decoded_audio.data.len();
-
-                if length == 0 {
+            BackgroundTask::BuildEngines(generation, engine_config) => {
+                // Requests that were superseded before their turn came are skipped entirely
+                if !loader.is_current(generation) {
                     return;
                 }
-
-                let length = if length > 2 { 2 } else { length };
-
-                let mut engines = std::vec::Vec::with_capacity(length);
-                for i in 0..length {
-                    engines.push(ConvolutionEngine::new(&decoded_audio.data[i], 1024));
+                let Some(impulse_response) = loader.impulse() else {
+                    return;
+                };
+
+                match build_engines(&impulse_response, engine_config, &loader, generation) {
+                    Ok(Some((engines, routing))) => {
+                        let loaded = LoadedIr {
+                            generation,
+                            engines: Some(engines),
+                            routing,
+                        };
+                        if loader.publish(loaded, impulse_response.clone()) {
+                            *impulse.lock().unwrap_or_else(PoisonError::into_inner) =
+                                impulse_response.to_vec();
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(error) => loader.fail(generation, error),
                 }
-
-                *impulse.lock().unwrap() = impulse_response;
-                tx.send(Message::Engine(engines)).unwrap();
+            }
+            BackgroundTask::DropEngines(engines) => {
+                drop(engines);
+            }
+            BackgroundTask::DropLoaded(loaded) => {
+                drop(loaded);
             }
         })
     }
 }
 
+impl ConvolutionReverb {
+    fn bypass_target(&self) -> f32 {
+        if self.bypassed {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn current_engine_config(&self) -> EngineConfig {
+        EngineConfig {
+            sample_rate: self.sample_rate,
+            num_channels: self.num_channels,
+            max_block_size: self.max_block_size,
+            routing: self.params.routing.value(),
+            latency: self.params.latency.value(),
+            quality: self.params.quality.value(),
+            ir_storage: self.params.ir_storage.value(),
+            max_ir_length: self.params.max_ir_length.value(),
+            memory_budget: (self.params.memory_budget.value() as usize) << 20,
+            truncate_long_irs: self.params.truncate_long_irs.value(),
+            threaded_tail: self.params.threaded_tail.value(),
+        }
+    }
+}
+
+/// Decodes the impulse response and builds an engine for every path of the routing on a thread
+/// pool of its own, reporting the progress to `loader`. Gives up and returns `Ok(None)` as soon as `generation` is
+/// stale.
+fn build_engines(
+    impulse_response: &[u8],
+    engine_config: EngineConfig,
+    loader: &IrLoader,
+    generation: u64,
+) -> Result<Option<(EngineSet, Routing)>, LoadError> {
+    let is_stale = || !loader.is_current(generation);
+    // Stale builds stop at the next report
+    let report = |stage: LoadStage, progress: f32| {
+        loader.report_progress(generation, stage, progress);
+        if is_stale() {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    };
+    // Checked before the copy of the file the decoder reads from, which counts as well
+    let budget = engine_config.memory_budget;
+    if impulse_response.len() > budget {
+        return Err(LoadError::OverBudget { budget });
+    }
+
+    // Files that are too long are rejected or cut short before they are decoded, if they tell
+    // their length
+    loader.report_progress(generation, LoadStage::Decoding, 0.0);
+    let limits = Limits {
+        max_seconds: engine_config.max_ir_length,
+        truncate: engine_config.truncate_long_irs,
+        budget,
+    };
+    let decoded = decoder::decode(impulse_response.to_vec(), limits, |progress| {
+        report(LoadStage::Decoding, progress)
+    })?;
+    let Some(decoded) = decoded else {
+        return Ok(None);
+    };
+
+    // Without resampling, the impulse response would play at the wrong speed
+    let sample_rate = engine_config.sample_rate;
+    if sample_rate == 0 {
+        return Err(LoadError::SampleRate {
+            from: decoded.sample_rate,
+            to: sample_rate,
+        });
+    }
+    let mut data = decoded.channels;
+    if decoded.sample_rate != sample_rate {
+        let num_channels = data.len();
+        for (i, channel) in data.iter_mut().enumerate() {
+            let resampled =
+                resample::resample(channel, decoded.sample_rate, sample_rate, |done, total| {
+                    let progress = (i as f32 + done as f32 / total as f32) / num_channels as f32;
+                    report(LoadStage::Resampling, progress)
+                });
+            let Some(resampled) = resampled else {
+                return Ok(None);
+            };
+            *channel = resampled;
+        }
+    }
+
+    let max_frames = (engine_config.max_ir_length * sample_rate as f32) as usize;
+    if decoded.truncated {
+        for channel in &mut data {
+            truncate_with_fade(channel, max_frames);
+        }
+    }
+    let length = data.len();
+    let frames = data[0].len();
+    if is_stale() {
+        return Ok(None);
+    }
+
+    let routing = Routing::resolve(
+        engine_config.routing.preferred(),
+        length,
+        engine_config.num_channels,
+    );
+    let engine_channels = routing.engine_channels(length, engine_config.num_channels);
+    let options = engine_config.engine_options();
+
+    // Checked before any engine is built
+    if engine_config.peak_memory(length, engine_channels.len(), frames) > budget {
+        return Err(LoadError::OverBudget { budget });
+    }
+
+    // The progress of the transforms, counted in samples of all engines together
+    let total = (engine_channels.len() * frames) as f32;
+    let report_transform = |samples: usize| report(LoadStage::Transforming, samples as f32 / total);
+    // Only kept for this build, dropping it stops its threads
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |threads| threads.get())
+        .min(MAX_PREPARATION_THREADS);
+    let pool = ThreadPool::new(threads);
+    let engines = match engine_config.quality {
+        Quality::Single => {
+            let channels: Vec<Arc<[f32]>> = engine_channels
+                .iter()
+                .map(|&c| data[c][..].into())
+                .collect();
+            ConvolutionEngine::build_all(&pool, &channels, options, report_transform)
+                .map(EngineSet::Single)
+        }
+        Quality::Double => {
+            let channels: Vec<Arc<[f64]>> = engine_channels
+                .iter()
+                .map(|&c| data[c].iter().map(|&s| s as f64).collect())
+                .collect();
+            ConvolutionEngine::build_all(&pool, &channels, options, report_transform)
+                .map(EngineSet::Double)
+        }
+    };
+    let Some(engines) = engines else {
+        return Ok(None);
+    };
+    nih_log!(
+        "Memory used by the engines: {} bytes",
+        engines.memory_footprint()
+    );
+
+    Ok(Some((engines, routing)))
+}
+
+/// Cuts `samples` off at `length` samples, and fades out the end with a raised cosine.
+fn truncate_with_fade(samples: &mut Vec<f32>, length: usize) {
+    // Decoding stops at the end of a packet, and resampling rounds, so this may be short already
+    let length = usize::min(length, samples.len());
+    samples.truncate(length);
+    let fade_length = (length as f32 * TRUNCATION_FADE) as usize;
+    let fade = &mut samples[length - fade_length..];
+    for (i, s) in fade.iter_mut().enumerate() {
+        let t = (i + 1) as f32 / fade_length as f32;
+        *s *= 0.5 + 0.5 * (std::f32::consts::PI * t).cos();
+    }
+}
+
 impl ClapPlugin for ConvolutionReverb {
     const CLAP_ID: &'static str = "com.example.convolution";
     const CLAP_DESCRIPTION: Option<&'static str> = Some("convolution reverb");
@@ -311,3 +846,135 @@ impl Vst3Plugin for ConvolutionReverb {
 
 nih_export_clap!(ConvolutionReverb);
 nih_export_vst3!(ConvolutionReverb);
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_engines, truncate_with_fade, EngineConfig, IrStorage, LatencyMode, Quality,
+        RoutingMode, TRUNCATION_FADE,
+    };
+    use crate::convolution::ConvolutionEngine;
+    use crate::loader::{IrLoader, LoadError};
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// A mono 16-bit WAV file with `frames` samples of a decaying impulse response.
+    fn wav(frames: usize) -> Vec<u8> {
+        let data_size = (frames * 2) as u32;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        // Integer PCM with one channel
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        // The bytes per second and per frame, and the bits per sample
+        bytes.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_size.to_le_bytes());
+        for i in 0..frames {
+            let sample = 16_000.0 * (-(i as f32) / 2_000.0).exp();
+            bytes.extend_from_slice(&(sample as i16).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn config(max_ir_length: f32, memory_budget: usize, truncate_long_irs: bool) -> EngineConfig {
+        EngineConfig {
+            sample_rate: SAMPLE_RATE,
+            num_channels: 2,
+            max_block_size: 512,
+            routing: RoutingMode::Auto,
+            latency: LatencyMode::Normal,
+            quality: Quality::Single,
+            ir_storage: IrStorage::Full,
+            max_ir_length,
+            memory_budget,
+            truncate_long_irs,
+            threaded_tail: false,
+        }
+    }
+
+    /// Builds the engines for `file`, and returns whether there were any.
+    fn build(file: &[u8], engine_config: EngineConfig) -> Result<bool, LoadError> {
+        let loader = IrLoader::default();
+        let generation = loader.request(file.to_vec());
+        build_engines(file, engine_config, &loader, generation).map(|engines| engines.is_some())
+    }
+
+    #[test]
+    fn truncation_fades_out() {
+        let mut samples = vec![1.0; 1000];
+        truncate_with_fade(&mut samples, 500);
+        assert_eq!(samples.len(), 500);
+
+        let fade_length = (500.0 * TRUNCATION_FADE) as usize;
+        let (kept, fade) = samples.split_at(500 - fade_length);
+        assert!(kept.iter().all(|&s| s == 1.0));
+        assert!(fade.windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(fade[0] < 1.0);
+        assert_eq!(fade.last(), Some(&0.0));
+
+        // Samples that are short already are faded out all the same
+        let mut samples = vec![1.0; 100];
+        truncate_with_fade(&mut samples, 200);
+        assert_eq!(samples.len(), 100);
+        assert_eq!(samples.last(), Some(&0.0));
+    }
+
+    #[test]
+    fn rejects_long_irs_unless_truncating() {
+        let file = wav(SAMPLE_RATE as usize);
+        let result = build(&file, config(0.5, 64 << 20, false));
+        match result {
+            Err(LoadError::TooLong {
+                seconds,
+                max_seconds,
+            }) => {
+                assert_eq!(seconds, 1.0);
+                assert_eq!(max_seconds, 0.5);
+            }
+            other => panic!("expected the impulse response to be too long, got {other:?}"),
+        }
+
+        assert!(build(&file, config(0.5, 64 << 20, true)).unwrap());
+        assert!(build(&file, config(2.0, 64 << 20, false)).unwrap());
+    }
+
+    #[test]
+    fn keeps_to_the_memory_budget() {
+        let frames = SAMPLE_RATE as usize;
+        let file = wav(frames);
+
+        // The file alone is over the budget
+        let budget = file.len() - 1;
+        assert!(matches!(
+            build(&file, config(2.0, budget, false)),
+            Err(LoadError::OverBudget { budget: b }) if b == budget
+        ));
+
+        // The file and the decoded samples fit, but not the engines
+        let budget = file.len() + frames * size_of::<f32>() + 1024;
+        assert!(matches!(
+            build(&file, config(2.0, budget, false)),
+            Err(LoadError::OverBudget { budget: b }) if b == budget
+        ));
+
+        // A mono impulse response gets an engine for each of the two channels. The engines fit,
+        // but not the copies of the samples and the spectra they are built from.
+        let options = config(2.0, 0, false).engine_options();
+        let engines = 2 * ConvolutionEngine::<f32>::memory_footprint_for(frames, options);
+        let budget = frames * size_of::<f32>() + engines;
+        assert!(matches!(
+            build(&file, config(2.0, budget, false)),
+            Err(LoadError::OverBudget { budget: b }) if b == budget
+        ));
+
+        let peak = config(2.0, 0, false).peak_memory(1, 2, frames);
+        assert!(build(&file, config(2.0, peak, false)).unwrap());
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::convolution::Routing;
+use crate::mailbox::Mailbox;
+use crate::plugin::EngineSet;
+
+/// Hands impulse responses from the editor to the background thread, and the engines built from
+/// them to the audio thread.
+///
+/// Every load gets a new generation, and so does every rebuild for a new configuration. Only the
+/// latest generation is ever published, so when several builds overlap, the last impulse response
+/// that was opened wins, not the last build to finish. Builds of older generations are cancelled.
+/// When a load fails, the engines of the previous impulse response keep playing.
+#[derive(Debug, Default)]
+pub struct IrLoader {
+    generation: AtomicU64,
+    /// The impulse response of the latest request, as the contents of the file.
+    impulse: Mutex<Option<Arc<Vec<u8>>>>,
+    /// The impulse response the latest published engines were built from.
+    loaded: Mutex<Option<Arc<Vec<u8>>>>,
+    /// Why the latest load failed, until the next one is requested or engines are published.
+    error: Mutex<Option<LoadError>>,
+    /// The [`LoadStage`] of the latest generation.
+    stage: AtomicU8,
+    /// How much of the work of `stage` is done, as the bits of an `f32` between zero and one.
+    progress: AtomicU32,
+    /// Held while publishing, so a newer generation can't be published in between the check and
+    /// the publication of an older one.
+    publishing: Mutex<()>,
+    engines: Mailbox<LoadedIr>,
+}
+
+/// Why an impulse response could not be loaded.
+#[derive(Debug, Clone)]
+pub enum LoadError {
+    /// The file could not be read.
+    Io(Arc<std::io::Error>),
+    /// The file could not be decoded, for example because the codec is not supported.
+    Unsupported(String),
+    /// The file does not contain any samples.
+    Empty,
+    /// The impulse response could not be resampled to the sample rate of the session.
+    SampleRate { from: u32, to: u32 },
+    /// The impulse response is longer than the maximum length, and truncating it is turned off.
+    TooLong { seconds: f32, max_seconds: f32 },
+    /// Decoding the impulse response or building the engines would take more memory than the
+    /// budget.
+    OverBudget { budget: usize },
+}
+
+impl LoadError {
+    /// Whether the impulse response may load with different settings, in which case it stays the
+    /// one that is rebuilt when the settings change.
+    pub fn depends_on_settings(&self) -> bool {
+        matches!(
+            self,
+            LoadError::TooLong { .. } | LoadError::OverBudget { .. }
+        )
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "Could not read the file: {error}"),
+            LoadError::Unsupported(error) => write!(f, "Could not decode the file: {error}"),
+            LoadError::Empty => write!(f, "The file does not contain any samples"),
+            LoadError::SampleRate { from, to } => {
+                write!(f, "Could not resample the file from {from} Hz to {to} Hz")
+            }
+            LoadError::TooLong {
+                seconds,
+                max_seconds,
+            } => write!(
+                f,
+                "The impulse response is {seconds:.1} s long, the maximum is {max_seconds:.1} s. \
+                 Turn on Truncate Long IRs to fade it out at the maximum length."
+            ),
+            LoadError::OverBudget { budget } => write!(
+                f,
+                "The impulse response needs more than the memory budget of {} MiB",
+                budget >> 20
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// What the build of the latest generation is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadStage {
+    Idle,
+    /// Decoding the file.
+    Decoding,
+    /// Resampling the impulse response to the sample rate of the session.
+    Resampling,
+    /// Transforming the partitions of the impulse response.
+    Transforming,
+    /// Waiting for the audio thread to pick up the engines.
+    HandingOff,
+}
+
+impl LoadStage {
+    const ALL: [LoadStage; 5] = [
+        LoadStage::Idle,
+        LoadStage::Decoding,
+        LoadStage::Resampling,
+        LoadStage::Transforming,
+        LoadStage::HandingOff,
+    ];
+}
+
+impl fmt::Display for LoadStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LoadStage::Idle => "",
+            LoadStage::Decoding => "Decoding",
+            LoadStage::Resampling => "Resampling",
+            LoadStage::Transforming => "Transforming partitions",
+            LoadStage::HandingOff => "Handing off",
+        })
+    }
+}
+
+/// The engines for one generation.
+#[derive(Debug)]
+pub struct LoadedIr {
+    pub generation: u64,
+    /// Taken out by the audio thread, which then sends the empty box back to be freed.
+    pub engines: Option<EngineSet>,
+    pub routing: Routing,
+}
+
+impl IrLoader {
+    /// Requests engines for a new impulse response. Not real-time safe.
+    pub fn request(&self, impulse: Vec<u8>) -> u64 {
+        let mut current = lock(&self.impulse);
+        *current = Some(Arc::new(impulse));
+        *lock(&self.error) = None;
+        self.generation.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Reports an error that happened before the impulse response could be requested.
+    pub fn report(&self, error: LoadError) {
+        *lock(&self.error) = Some(error);
+    }
+
+    /// Gives up on `generation`, unless it is stale already. Unless the error could go away with
+    /// other settings, the previous impulse response becomes the current one again, so it is the
+    /// one that gets rebuilt when the configuration changes.
+    pub fn fail(&self, generation: u64, error: LoadError) {
+        let mut current = lock(&self.impulse);
+        if self.is_current(generation) {
+            if !error.depends_on_settings() {
+                *current = lock(&self.loaded).clone();
+            }
+            *lock(&self.error) = Some(error);
+            self.set_progress(LoadStage::Idle, 0.0);
+        }
+    }
+
+    /// Reports how far the build of `generation` got, unless it is stale. A stale build can still
+    /// slip in one last report, which the next report of the current build overwrites.
+    pub fn report_progress(&self, generation: u64, stage: LoadStage, progress: f32) {
+        if self.is_current(generation) {
+            self.set_progress(stage, progress);
+        }
+    }
+
+    /// Marks `generation` as loaded once the audio thread has swapped in its engines. Wait-free.
+    pub fn finish(&self, generation: u64) {
+        self.report_progress(generation, LoadStage::Idle, 0.0);
+    }
+
+    /// The stage of the latest generation, and how much of its work is done.
+    pub fn progress(&self) -> (LoadStage, f32) {
+        let stage = LoadStage::ALL[self.stage.load(Ordering::Relaxed) as usize];
+        let progress = f32::from_bits(self.progress.load(Ordering::Relaxed));
+        (stage, progress)
+    }
+
+    fn set_progress(&self, stage: LoadStage, progress: f32) {
+        self.stage.store(stage as u8, Ordering::Relaxed);
+        self.progress.store(progress.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Why the latest load failed, if it did.
+    pub fn error(&self) -> Option<LoadError> {
+        lock(&self.error).clone()
+    }
+
+    /// Requests new engines for the current impulse response, cancelling the builds in flight.
+    /// Wait-free, so this can be called from the audio thread.
+    pub fn invalidate(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// The latest generation.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Whether `generation` is still the latest one. Builds check this to stop early once they
+    /// are stale.
+    pub fn is_current(&self, generation: u64) -> bool {
+        self.generation() == generation
+    }
+
+    /// The impulse response of the latest request, if any.
+    pub fn impulse(&self) -> Option<Arc<Vec<u8>>> {
+        lock(&self.impulse).clone()
+    }
+
+    /// Hands the engines built from `impulse` to the audio thread, unless a newer generation was
+    /// requested in the meantime. Engines that were published before but not picked up yet are
+    /// dropped. Returns whether the engines were published.
+    pub fn publish(&self, loaded: LoadedIr, impulse: Arc<Vec<u8>>) -> bool {
+        let _publishing = lock(&self.publishing);
+        if !self.is_current(loaded.generation) {
+            return false;
+        }
+
+        *lock(&self.loaded) = Some(impulse);
+        // A rebuild with new settings can succeed where the previous build failed
+        *lock(&self.error) = None;
+        self.set_progress(LoadStage::HandingOff, 1.0);
+        drop(self.engines.put(Box::new(loaded)));
+        true
+    }
+
+    /// Takes the engines that were published last. Wait-free, neither allocates nor frees.
+    pub fn take(&self) -> Option<Box<LoadedIr>> {
+        self.engines.take()
+    }
+}
+
+/// Locks `mutex`. A panic while it was held can't leave any of the loader's state inconsistent,
+/// since every value is replaced as a whole.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IrLoader, LoadError, LoadStage, LoadedIr};
+    use crate::convolution::Routing;
+    use std::sync::Arc;
+
+    fn loaded(generation: u64) -> LoadedIr {
+        LoadedIr {
+            generation,
+            engines: None,
+            routing: Routing::Parallel,
+        }
+    }
+
+    #[test]
+    fn publishes_only_the_latest_generation() {
+        let loader = IrLoader::default();
+        let first = loader.request(vec![1]);
+        let second = loader.request(vec![2]);
+        assert!(second > first);
+
+        assert!(!loader.publish(loaded(first), Arc::new(vec![1])));
+        assert!(loader.take().is_none());
+        assert!(loader.publish(loaded(second), Arc::new(vec![2])));
+        assert_eq!(loader.take().map(|l| l.generation), Some(second));
+
+        // Rebuilding makes the engines in flight stale as well
+        let rebuild = loader.invalidate();
+        assert!(!loader.publish(loaded(second), Arc::new(vec![2])));
+        assert!(loader.publish(loaded(rebuild), Arc::new(vec![2])));
+    }
+
+    #[test]
+    fn ignores_progress_of_stale_generations() {
+        let loader = IrLoader::default();
+        assert_eq!(loader.progress(), (LoadStage::Idle, 0.0));
+
+        let stale = loader.request(vec![1]);
+        let current = loader.request(vec![2]);
+        loader.report_progress(current, LoadStage::Transforming, 0.5);
+        loader.report_progress(stale, LoadStage::Decoding, 0.0);
+        assert_eq!(loader.progress(), (LoadStage::Transforming, 0.5));
+        for stage in LoadStage::ALL {
+            loader.report_progress(current, stage, 0.25);
+            assert_eq!(loader.progress(), (stage, 0.25));
+        }
+
+        loader.publish(loaded(current), Arc::new(vec![2]));
+        assert_eq!(loader.progress(), (LoadStage::HandingOff, 1.0));
+        loader.finish(current);
+        assert_eq!(loader.progress(), (LoadStage::Idle, 0.0));
+    }
+
+    #[test]
+    fn failure_restores_the_previous_impulse() {
+        let loader = IrLoader::default();
+        let good = loader.request(vec![1]);
+        loader.publish(loaded(good), loader.impulse().unwrap());
+
+        let bad = loader.request(vec![2]);
+        loader.fail(bad, LoadError::Empty);
+        assert_eq!(loader.impulse().as_deref(), Some(&vec![1]));
+        assert!(matches!(loader.error(), Some(LoadError::Empty)));
+
+        // Changing the settings retries an impulse response that was too long
+        let long = loader.request(vec![3]);
+        assert!(loader.error().is_none());
+        loader.fail(
+            long,
+            LoadError::TooLong {
+                seconds: 60.0,
+                max_seconds: 30.0,
+            },
+        );
+        assert_eq!(loader.impulse().as_deref(), Some(&vec![3]));
+
+        // Which clears the error once it succeeds
+        let retry = loader.invalidate();
+        loader.publish(loaded(retry), loader.impulse().unwrap());
+        assert!(loader.error().is_none());
+    }
+}
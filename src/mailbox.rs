@@ -0,0 +1,106 @@
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// A slot that holds at most one value. Putting a value replaces the one that has not been taken
+/// yet. Both sides are a single atomic swap, so they are wait-free, and neither allocates nor
+/// frees: the boxes are allocated by the caller of [`Mailbox::put`] and handed back to callers.
+pub struct Mailbox<T> {
+    slot: AtomicPtr<T>,
+}
+
+// SAFETY: The mailbox owns the boxed value, which only one side gets at a time
+unsafe impl<T: Send> Send for Mailbox<T> {}
+unsafe impl<T: Send> Sync for Mailbox<T> {}
+
+impl<T> Mailbox<T> {
+    pub fn new() -> Self {
+        Self {
+            slot: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Puts `value` into the slot, and returns the value it replaced, which was never taken.
+    pub fn put(&self, value: Box<T>) -> Option<Box<T>> {
+        let replaced = self.slot.swap(Box::into_raw(value), Ordering::AcqRel);
+        // SAFETY: Every non-null pointer in the slot came from `Box::into_raw`, and the swap gave
+        // up the slot's ownership of it
+        (!replaced.is_null()).then(|| unsafe { Box::from_raw(replaced) })
+    }
+
+    /// Takes the value out of the slot.
+    pub fn take(&self) -> Option<Box<T>> {
+        let taken = self.slot.swap(ptr::null_mut(), Ordering::AcqRel);
+        // SAFETY: See `put`
+        (!taken.is_null()).then(|| unsafe { Box::from_raw(taken) })
+    }
+}
+
+impl<T> Default for Mailbox<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
+
+impl<T> std::fmt::Debug for Mailbox<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let full = !self.slot.load(Ordering::Relaxed).is_null();
+        f.debug_struct("Mailbox").field("full", &full).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mailbox;
+    use std::sync::Arc;
+
+    #[test]
+    fn keeps_the_latest_value() {
+        let mailbox = Mailbox::new();
+        assert!(mailbox.take().is_none());
+
+        assert!(mailbox.put(Box::new(1)).is_none());
+        assert_eq!(mailbox.put(Box::new(2)).as_deref(), Some(&1));
+        assert_eq!(mailbox.take().as_deref(), Some(&2));
+        assert!(mailbox.take().is_none());
+    }
+
+    #[test]
+    fn drops_the_value_left_behind() {
+        let value = Arc::new(());
+        let mailbox = Mailbox::new();
+        mailbox.put(Box::new(value.clone()));
+        drop(mailbox);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn every_value_is_taken_or_replaced_once() {
+        const COUNT: usize = 10_000;
+        let mailbox = Arc::new(Mailbox::new());
+        let producer = {
+            let mailbox = mailbox.clone();
+            std::thread::spawn(move || {
+                (0..COUNT)
+                    .filter_map(|i| mailbox.put(Box::new(i)))
+                    .map(|replaced| *replaced)
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let mut taken = Vec::new();
+        while !producer.is_finished() {
+            taken.extend(mailbox.take().map(|value| *value));
+        }
+        let mut values = producer.join().unwrap();
+        values.extend(taken);
+        values.extend(mailbox.take().map(|value| *value));
+        values.sort_unstable();
+        assert_eq!(values, (0..COUNT).collect::<Vec<_>>());
+    }
+}
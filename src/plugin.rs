@@ -1,27 +1,120 @@
 use crate::convolution::Convolution;
 use crate::convolution::ConvolutionEngine;
+use crate::convolution::Routing;
 use crate::PlugParams;
 use std::vec::Vec;
 
+/// The engines for one impulse response, at the precision they were built for.
+#[derive(Debug)]
+pub enum EngineSet {
+    Single(Vec<ConvolutionEngine<f32>>),
+    Double(Vec<ConvolutionEngine<f64>>),
+}
+
+impl EngineSet {
+    /// The memory used by the engines in bytes.
+    pub fn memory_footprint(&self) -> usize {
+        match self {
+            EngineSet::Single(engines) => engines.iter().map(|e| e.memory_footprint()).sum(),
+            EngineSet::Double(engines) => engines.iter().map(|e| e.memory_footprint()).sum(),
+        }
+    }
+}
+
 pub struct AudioPlugin {
-    convolution_node: Convolution,
+    convolution_node: Convolution<f32>,
+    /// Runs instead of `convolution_node` while it has engines.
+    double_node: Convolution<f64>,
     sample_rate: usize,
     buffer_size: usize,
     input_buffer: Vec<f32>,
+    /// The input and output of `double_node`, converted from and to `f32`.
+    double_inputs: [Vec<f64>; 2],
+    double_outputs: [Vec<f64>; 2],
 }
 
 impl AudioPlugin {
     pub fn new() -> Self {
         Self {
-            convolution_node: Convolution::new(1024),
+            convolution_node: Convolution::new(0),
+            double_node: Convolution::new(0),
             sample_rate: 0,
             buffer_size: 0,
             input_buffer: Vec::new(),
+            double_inputs: [Vec::new(), Vec::new()],
+            double_outputs: [Vec::new(), Vec::new()],
+        }
+    }
+
+    /// Prepares for blocks of at most `buffer_size` samples. Allocates, so this must not be called
+    /// from the audio thread.
+    pub fn initialize(&mut self, sample_rate: usize, buffer_size: usize) {
+        self.sample_rate = sample_rate;
+        self.buffer_size = buffer_size;
+        self.convolution_node.set_max_block_size(buffer_size);
+        self.double_node.set_max_block_size(buffer_size);
+        for buffer in self
+            .double_inputs
+            .iter_mut()
+            .chain(&mut self.double_outputs)
+        {
+            buffer.resize(buffer_size, 0.0);
+        }
+    }
+
+    /// Replaces the current engines. Engines of the same precision are crossfaded, switching the
+    /// precision cuts over immediately.
+    pub fn swap(&mut self, engines: EngineSet, routing: Routing, crossfade_length: usize) {
+        match engines {
+            EngineSet::Single(engines) => {
+                self.double_node.clear();
+                self.convolution_node
+                    .swap(engines, routing, crossfade_length);
+            }
+            EngineSet::Double(engines) => {
+                self.convolution_node.clear();
+                self.double_node.swap(engines, routing, crossfade_length);
+            }
+        }
+    }
+
+    /// Makes the engines wait for their worker threads, which offline rendering needs to be the
+    /// same every time.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.convolution_node.set_wait_for_worker(offline);
+        self.double_node.set_wait_for_worker(offline);
+    }
+
+    pub fn reset(&mut self) {
+        self.convolution_node.reset();
+        self.double_node.reset();
+    }
+
+    pub fn has_engines(&self) -> bool {
+        self.convolution_node.has_engines() || self.double_node.has_engines()
+    }
+
+    pub fn latency(&self) -> usize {
+        if self.double_node.has_engines() {
+            self.double_node.latency()
+        } else {
+            self.convolution_node.latency()
         }
     }
 
-    pub fn swap(&mut self, engines: Vec<ConvolutionEngine>) {
-        self.convolution_node.swap(engines);
+    pub fn tail_length(&self) -> usize {
+        if self.double_node.has_engines() {
+            self.double_node.tail_length()
+        } else {
+            self.convolution_node.tail_length()
+        }
+    }
+
+    pub fn take_retired(&mut self) -> impl Iterator<Item = EngineSet> + '_ {
+        self.convolution_node
+            .take_retired()
+            .map(EngineSet::Single)
+            .chain(self.double_node.take_retired().map(EngineSet::Double))
     }
 
     pub fn process<I, O>(&mut self, input: &[I], output: &mut [O], params: &PlugParams)
@@ -29,6 +122,60 @@ impl AudioPlugin {
         I: AsRef<[f32]>,
         O: AsMut<[f32]>,
     {
-        self.convolution_node.process(input, output);
+        // Swapping the output buffers swaps the channels of the wet signal
+        let swap_channels = params.swap_channels.value() && output.len() == 2;
+        if swap_channels {
+            output.swap(0, 1);
+        }
+        if self.double_node.has_engines() {
+            self.process_double(input, output);
+        } else {
+            self.convolution_node.process(input, output);
+        }
+        if swap_channels {
+            output.swap(0, 1);
+        }
+
+        if self.has_engines() {
+            let polarities = [params.invert_left.value(), params.invert_right.value()];
+            for (o, invert) in output.iter_mut().zip(polarities) {
+                if invert {
+                    for s in o.as_mut() {
+                        *s = -*s;
+                    }
+                }
+            }
+        }
+    }
+
+    fn process_double<I, O>(&mut self, input: &[I], output: &mut [O])
+    where
+        I: AsRef<[f32]>,
+        O: AsMut<[f32]>,
+    {
+        let num_samples = input.first().map_or(0, |i| i.as_ref().len());
+        let [left, right] = &mut self.double_inputs;
+        let inputs = &mut [&mut left[..num_samples], &mut right[..num_samples]][..input.len()];
+        for (wide, i) in inputs.iter_mut().zip(input) {
+            for (w, s) in wide.iter_mut().zip(i.as_ref()) {
+                *w = *s as f64;
+            }
+        }
+
+        // Channels the engines don't write to keep their contents, like in the `f32` path
+        let [left, right] = &mut self.double_outputs;
+        let outputs = &mut [&mut left[..num_samples], &mut right[..num_samples]][..output.len()];
+        for (wide, o) in outputs.iter_mut().zip(output.iter_mut()) {
+            for (w, s) in wide.iter_mut().zip(o.as_mut().iter()) {
+                *w = *s as f64;
+            }
+        }
+        self.double_node.process(inputs, outputs);
+
+        for (o, wide) in output.iter_mut().zip(outputs.iter()) {
+            for (o, w) in o.as_mut().iter_mut().zip(wide.iter()) {
+                *o = *w as f32;
+            }
+        }
     }
 }
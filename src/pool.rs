@@ -0,0 +1,103 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads that run jobs in the order they were submitted. Everything that is
+/// submitted shares the same threads, so the bound holds no matter how the work is split up.
+/// Dropping the pool waits for the jobs that were submitted already.
+pub struct ThreadPool {
+    sender: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(num_threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..num_threads.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(format!("convolution preparation {i}"))
+                    .spawn(move || run(&receiver))
+                    .expect("the preparation thread could be spawned")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            threads,
+        }
+    }
+
+    /// Queues `job` to run on one of the threads.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.sender
+            .as_ref()
+            .expect("the pool has not been dropped")
+            .send(Box::new(job))
+            .expect("the preparation threads are running");
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // The threads stop once the queue is empty and closed
+        self.sender = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = receiver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv();
+        let Ok(job) = job else {
+            return;
+        };
+        // A job that panics drops its end of whatever it reports to, which tells the submitter.
+        // The thread stays around for the next job.
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadPool;
+    use std::sync::mpsc;
+
+    #[test]
+    fn runs_every_job() {
+        let pool = ThreadPool::new(3);
+
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..100 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+        drop(sender);
+        let mut results: Vec<i32> = receiver.iter().collect();
+        results.sort();
+        assert_eq!(results, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let dropped = sender.clone();
+        pool.execute(move || {
+            let _sender = dropped;
+            panic!("the job failed");
+        });
+        pool.execute(move || sender.send(()).unwrap());
+        assert!(receiver.recv().is_ok());
+        assert!(receiver.recv().is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use rubato::{FftFixedInOut, Resampler};
+use std::ops::ControlFlow;
+
+/// The number of input samples resampled at once, and between progress reports. This is rounded
+/// up to whole periods of the two sample rates.
+const CHUNK_SIZE: usize = 4096;
+
+/// Resamples `input` from `from` Hz to `to` Hz with rubato's synchronous FFT resampler, which is
+/// what symphonium uses for its high quality setting. The output is not delayed, and it's as long
+/// as the input in time, rounded up.
+///
+/// `on_progress` is called with the number of input samples done and the input length for every
+/// chunk. Returns `None` if it breaks.
+pub fn resample(
+    input: &[f32],
+    from: u32,
+    to: u32,
+    mut on_progress: impl FnMut(usize, usize) -> ControlFlow<()>,
+) -> Option<Vec<f32>> {
+    let new_resampler = |chunk_size| {
+        FftFixedInOut::<f32>::new(from as usize, to as usize, chunk_size, 1)
+            .expect("the sample rates are not zero")
+    };
+    // The filter is centered on the middle of a chunk, which only falls on an output sample if a
+    // chunk holds an even number of periods. One more period fixes that, since the resampler would
+    // otherwise round the delay down.
+    let mut resampler = new_resampler(CHUNK_SIZE);
+    let chunk_size = resampler.input_frames_next() as u64;
+    if !chunk_size.is_multiple_of(2)
+        || !(chunk_size / 2 * u64::from(to)).is_multiple_of(u64::from(from))
+    {
+        resampler = new_resampler(chunk_size as usize + 1);
+    }
+    let mut buffer = resampler.output_buffer_allocate(true);
+
+    // The delay is cut off again at the end. Past the end of the input, the resampler is fed
+    // silence until the rest of the filter's output has come out.
+    let delay = resampler.output_delay();
+    let output_length = (input.len() as u64 * u64::from(to)).div_ceil(u64::from(from)) as usize;
+    let mut output = Vec::with_capacity(delay + output_length + buffer[0].len());
+    let mut position = 0;
+    while output.len() < delay + output_length {
+        if on_progress(position, input.len()).is_break() {
+            return None;
+        }
+
+        let remaining = &input[position..];
+        let needed = resampler.input_frames_next();
+        let (consumed, written) = if remaining.len() >= needed {
+            resampler.process_into_buffer(&[&remaining[..needed]], &mut buffer, None)
+        } else if !remaining.is_empty() {
+            resampler.process_partial_into_buffer(Some(&[remaining]), &mut buffer, None)
+        } else {
+            resampler.process_partial_into_buffer(None::<&[&[f32]]>, &mut buffer, None)
+        }
+        .expect("the buffers fit the resampler");
+        position = usize::min(position + consumed, input.len());
+        output.extend_from_slice(&buffer[0][..written]);
+    }
+    if on_progress(input.len(), input.len()).is_break() {
+        return None;
+    }
+
+    output.drain(..delay);
+    output.truncate(output_length);
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resample;
+    use std::f64::consts::TAU;
+    use std::ops::ControlFlow;
+
+    fn resample_all(input: &[f32], from: u32, to: u32) -> Vec<f32> {
+        resample(input, from, to, |_, _| ControlFlow::Continue(())).unwrap()
+    }
+
+    fn sine(frequency: f64, sample_rate: u32, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|n| (TAU * frequency * n as f64 / f64::from(sample_rate)).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_passband() {
+        for (from, to) in [
+            (44_100, 48_000),
+            (48_000, 44_100),
+            (96_000, 48_000),
+            (22_050, 48_000),
+        ] {
+            let output = resample_all(&sine(1000.0, from, from as usize), from, to);
+            assert_eq!(output.len(), to as usize);
+
+            // The edges only see half of the filter
+            let expected = sine(1000.0, to, to as usize);
+            let max_error = output[200..output.len() - 200]
+                .iter()
+                .zip(&expected[200..])
+                .map(|(o, e)| (o - e).abs())
+                .fold(0.0, f32::max);
+            println!("max error from {from} Hz to {to} Hz: {max_error}");
+            assert!(max_error < 1e-3);
+        }
+    }
+
+    #[test]
+    fn removes_what_the_output_cannot_represent() {
+        let output = resample_all(&sine(30_000.0, 96_000, 96_000), 96_000, 48_000);
+        let peak = output[200..output.len() - 200]
+            .iter()
+            .fold(0.0, |peak: f32, s| peak.max(s.abs()));
+        println!("peak of the alias: {peak}");
+        // -60 dB
+        assert!(peak < 1e-3);
+    }
+
+    #[test]
+    fn reports_progress() {
+        let input = sine(1000.0, 44_100, 44_100);
+        let mut reports = Vec::new();
+        let output = resample(&input, 44_100, 48_000, |done, total| {
+            reports.push((done, total));
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(output, resample_all(&input, 44_100, 48_000));
+        assert_eq!(reports.first(), Some(&(0, 44_100)));
+        assert_eq!(reports.last(), Some(&(44_100, 44_100)));
+        assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        let mut calls = 0;
+        let cancelled = resample(&input, 44_100, 48_000, |_, _| {
+            calls += 1;
+            if calls == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert!(cancelled.is_none());
+        assert_eq!(calls, 2);
+    }
+}
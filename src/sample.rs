@@ -0,0 +1,68 @@
+use crate::kernel::Element;
+
+use realfft::FftNum;
+use rustfft::num_traits::{Float, FloatConst};
+use std::iter::Sum;
+use std::ops::{AddAssign, MulAssign, SubAssign};
+
+#[cfg(feature = "nightly-simd")]
+use std::simd::prelude::*;
+
+/// The floating point types the convolution can run at.
+pub trait Sample:
+    FftNum + Float + FloatConst + Element + AddAssign + SubAssign + MulAssign + Sum
+{
+    /// The dot product of `a` and `b`, summed in eight lanes.
+    fn dot(a: &[Self], b: &[Self]) -> Self;
+}
+
+macro_rules! impl_sample {
+    ($t:ty) => {
+        impl Sample for $t {
+            #[cfg(feature = "nightly-simd")]
+            fn dot(a: &[Self], b: &[Self]) -> Self {
+                let chunks_a = a.chunks_exact(8);
+                let chunks_b = b.chunks_exact(8);
+                let suffix = chunks_a
+                    .remainder()
+                    .iter()
+                    .zip(chunks_b.remainder())
+                    .map(|(a, b)| a * b)
+                    .sum::<Self>();
+
+                let mut sum = Simd::<Self, 8>::splat(0.0);
+                for (a, b) in chunks_a.zip(chunks_b) {
+                    sum += Simd::from_slice(a) * Simd::from_slice(b);
+                }
+
+                sum.reduce_sum() + suffix
+            }
+
+            /// The same sum as the SIMD version, with the lanes written out so it is
+            /// autovectorized.
+            #[cfg(not(feature = "nightly-simd"))]
+            fn dot(a: &[Self], b: &[Self]) -> Self {
+                let chunks_a = a.chunks_exact(8);
+                let chunks_b = b.chunks_exact(8);
+                let suffix = chunks_a
+                    .remainder()
+                    .iter()
+                    .zip(chunks_b.remainder())
+                    .map(|(a, b)| a * b)
+                    .sum::<Self>();
+
+                let mut sum = [0.0; 8];
+                for (a, b) in chunks_a.zip(chunks_b) {
+                    for i in 0..8 {
+                        sum[i] += a[i] * b[i];
+                    }
+                }
+
+                sum.iter().sum::<Self>() + suffix
+            }
+        }
+    };
+}
+
+impl_sample!(f32);
+impl_sample!(f64);